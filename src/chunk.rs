@@ -1,20 +1,53 @@
 use crate::*;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::collections::HashMap;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct LineRL {pub line: u32, pub repeat: u16}
 type LineVec = Vec<LineRL>;
 
-#[derive(Clone)]
+const QBC_MAGIC: &[u8] = b"QBC";
+const QBC_VERSION: u8 = 2;
+
+// identifies constants that can share one slot in the constant pool
+// numbers are compared by their bits so 0 and -0 stay apart
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Int(i64),
+    String(String)
+}
+
+impl ConstKey {
+    // None for values that must never be shared, like mutable heap objects
+    fn of(val: &Value) -> Option<ConstKey> {
+        match val {
+            Value::Null => Some(ConstKey::Null),
+            Value::Bool(b) => Some(ConstKey::Bool(*b)),
+            Value::Number(n) => Some(ConstKey::Number(n.to_bits())),
+            Value::Int(i) => Some(ConstKey::Int(*i)),
+            Value::String(s) => Some(ConstKey::String(s.clone())),
+            // symbols are interned per VM, PushSymbol takes the name instead
+            Value::Symbol(_) | Value::StringBuilder(_) | Value::Array(_) | Value::Map(_) => None
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Chunk {
     pub instrs: Vec<Instruction>,
     consts: Vec<Value>,
+    const_ids: HashMap<ConstKey, u32>,
     lines: LineVec
 }
 
 impl Chunk {
     pub fn add_line(&mut self, line: u32) {
         if let Some(last_line) = self.lines.last_mut() {
-            if last_line.line == line {
+            if last_line.line == line && last_line.repeat < u16::MAX {
                 last_line.repeat += 1;
                 return;
             }
@@ -27,17 +60,49 @@ impl Chunk {
         Chunk {
             instrs: Vec::<Instruction>::new(),
             consts: Vec::<Value>::new(),
+            const_ids: HashMap::new(),
             lines: LineVec::new()
         }
     }
-    pub fn add_const(&mut self, val: Value) -> u16 {
+    // equal constants are only stored once
+    pub fn add_const(&mut self, val: Value) -> u32 {
+        let key = ConstKey::of(&val);
+        if let Some(id) = key.as_ref().and_then(|key| self.const_ids.get(key)) {
+            return *id;
+        }
+        self.push_const(val, key)
+    }
+    fn push_const(&mut self, val: Value, key: Option<ConstKey>) -> u32 {
+        let id = self.consts.len() as u32;
         self.consts.push(val);
-        (self.consts.len() - 1) as u16
+        if let Some(key) = key {
+            self.const_ids.entry(key).or_insert(id);
+        }
+        id
+    }
+    // adds the constant and the instruction pushing it, wide if the id needs it
+    pub fn add_push_const(&mut self, val: Value, line_no: u32) {
+        let const_id = self.add_const(val);
+        match u16::try_from(const_id) {
+            Ok(id) => self.add_instr(Instruction::PushConstant(id), line_no),
+            Err(_) => self.add_instr(Instruction::PushConstantLong(const_id), line_no)
+        }
     }
-    pub fn get_const(&self, const_id: u16) -> &Value {
+    pub fn get_const(&self, const_id: u32) -> &Value {
         self.consts.get(const_id as usize)
             .expect("The VM failed to access a constant. This might be a problem with the interpreter itself.")
     }
+    pub fn try_get_const(&self, const_id: u32) -> Result<&Value, &'static str> {
+        self.consts.get(const_id as usize).ok_or("Constant out of range")
+    }
+    // a constant as the disassembly and the debug trace show it. They run
+    // before the VM checks the id, so a bad one is marked instead of panicking
+    fn const_info(&self, const_id: u32) -> String {
+        match self.try_get_const(const_id) {
+            Ok(val) => format!("{:?}", val),
+            Err(_) => "<out of range>".to_string()
+        }
+    }
     pub fn add_instr(&mut self, instr: Instruction, line_no: u32){
         self.instrs.push(instr);
         self.add_line(line_no);
@@ -71,6 +136,42 @@ impl Chunk {
         self.get_line_no(self.instrs.len() as u32-1)
     }
 
+    // lists the first differing instruction, constant and line, if any
+    // meant for comparing compiler output in tests
+    pub fn diff(&self, other: &Chunk) -> Vec<String> {
+        let mut diffs = Vec::<String>::new();
+
+        let instr_count = self.instrs.len().max(other.instrs.len());
+        for i in 0..instr_count {
+            let (a, b) = (self.instrs.get(i), other.instrs.get(i));
+            if a != b {
+                let prev = if i >= 1 {format!(" (after {:?})", self.instrs[i-1])} else {"".to_string()};
+                diffs.push(format!("instruction {}: {:?} != {:?}{}", i, a, b, prev));
+                break;
+            }
+        }
+
+        let const_count = self.consts.len().max(other.consts.len());
+        for i in 0..const_count {
+            let (a, b) = (self.consts.get(i), other.consts.get(i));
+            if a != b {
+                diffs.push(format!("constant {}: {:?} != {:?}", i, a, b));
+                break;
+            }
+        }
+
+        let line_count = self.instrs.len().min(other.instrs.len());
+        for i in 0..line_count {
+            let (a, b) = (self.get_line_no(i as u32), other.get_line_no(i as u32));
+            if a != b {
+                diffs.push(format!("line of instruction {}: {} != {}", i, a, b));
+                break;
+            }
+        }
+
+        diffs
+    }
+
     // pretty print
     pub fn print(&self, name: &'static str) {
         println!("== {} ==", name);
@@ -88,16 +189,223 @@ impl Chunk {
                 self.get_line_no(instr_id as u32).to_string()
             }
         );
-        self.print_instr_info(&self.get_instr(instr_id));
+        self.print_instr_info(self.get_instr(instr_id));
     }
+
+    // one line per instruction: index, source line, opcode and decoded operands
+    pub fn disassemble(&self) -> String {
+        let mut out = Vec::<u8>::new();
+        self.disassemble_to(&mut out).expect("Writing to a Vec cannot fail");
+        String::from_utf8(out).expect("The disassembly is always valid UTF-8")
+    }
+    pub fn disassemble_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (i, instr) in self.instrs.iter().enumerate() {
+            let operands = match instr {
+                Instruction::PushConstant(id) | Instruction::TraceLabeled(id) | Instruction::PushSymbol(id) =>
+                    format!("{} ({})", id, self.const_info(u32::from(*id))),
+                Instruction::PushConstantLong(id) => format!("{} ({})", id, self.const_info(*id)),
+                Instruction::JumpIfFalse(offset) | Instruction::Jump(offset)
+                | Instruction::And(offset) | Instruction::Or(offset)
+                | Instruction::PushTryHandler(offset) =>
+                    format!("{} (to {:04})", offset, i + 1 + *offset as usize),
+                Instruction::Loop(offset) =>
+                    format!("{} (to {:04})", offset, (i + 1).wrapping_sub(*offset as usize)),
+                Instruction::PushVariable(id) | Instruction::Assign(id) | Instruction::DupN(id)
+                | Instruction::Increment(id) | Instruction::Decrement(id)
+                | Instruction::GetGlobal(id) | Instruction::SetGlobal(id)
+                | Instruction::NewArray(id)
+                | Instruction::NewMap(id)
+                | Instruction::FormatStr(id) => id.to_string(),
+                Instruction::SwitchType(arms) => arms.iter()
+                    .map(|(name, offset)| format!("{} (to {:04})", name, i + 1 + *offset as usize))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                _ => "".to_string()
+            };
+            let line = format!("{:04} {:>4} {:<16} {}", i, self.get_line_no(i as u32), instr.mnemonic(), operands);
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+
+    // binary bytecode format (.qbc), all numbers little endian:
+    // magic, version, instructions (opcode [u32 operand]),
+    // constants (tag payload), line runs (u32 line, u16 repeat).
+    // SwitchType's operand is its arm count, followed by the arms as
+    // (u32 length, type name, u16 offset). Only plain values can be
+    // written, a chunk holding a symbol or a shared value like a string
+    // builder or a collection as a constant errors
+    pub fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        let mut out = Vec::<u8>::new();
+        out.extend_from_slice(QBC_MAGIC);
+        out.push(QBC_VERSION);
+
+        out.extend_from_slice(&(self.instrs.len() as u32).to_le_bytes());
+        for instr in self.instrs.iter() {
+            let (opcode, operand) = instr.encode();
+            out.push(opcode);
+            if let Some(operand) = operand {
+                out.extend_from_slice(&operand.to_le_bytes());
+            }
+            if let Instruction::SwitchType(arms) = instr {
+                for (name, offset) in arms.iter() {
+                    out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                    out.extend_from_slice(name.as_bytes());
+                    out.extend_from_slice(&offset.to_le_bytes());
+                }
+            }
+        }
+
+
+        out.extend_from_slice(&(self.consts.len() as u32).to_le_bytes());
+        for val in self.consts.iter() {
+            match val {
+                Value::Null => out.push(0),
+                Value::Bool(b) => {
+                    out.push(1);
+                    out.push(*b as u8);
+                },
+                Value::Number(n) => {
+                    out.push(2);
+                    out.extend_from_slice(&n.to_le_bytes());
+                },
+                Value::Int(i) => {
+                    out.push(4);
+                    out.extend_from_slice(&i.to_le_bytes());
+                },
+                Value::String(s) => {
+                    out.push(3);
+                    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                    out.extend_from_slice(s.as_bytes());
+                },
+                Value::Symbol(_) | Value::StringBuilder(_) | Value::Array(_) | Value::Map(_) =>
+                    return Err("Only plain values can be serialized as constants")
+            }
+        }
+
+        out.extend_from_slice(&(self.lines.len() as u32).to_le_bytes());
+        for l in self.lines.iter() {
+            out.extend_from_slice(&l.line.to_le_bytes());
+            out.extend_from_slice(&l.repeat.to_le_bytes());
+        }
+        Ok(out)
+    }
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, &'static str> {
+        let mut r = ByteReader {bytes, pos: 0};
+        if r.take(QBC_MAGIC.len())? != QBC_MAGIC {
+            return Err("Not a queso bytecode file");
+        }
+        if r.u8()? != QBC_VERSION {
+            return Err("Unsupported bytecode version");
+        }
+
+        let mut chk = Chunk::new();
+        for _ in 0..r.u32()? {
+            let opcode = r.u8()?;
+            // decode with a dummy operand first to learn whether an operand follows
+            let operand = match Instruction::decode(opcode, 0).map(|i| i.operand_count()) {
+                Some(1) => r.u32()?,
+                Some(_) => 0,
+                None => return Err("Unknown opcode")
+            };
+            let mut instr = Instruction::decode(opcode, operand).ok_or("Unknown opcode")?;
+            if let Instruction::SwitchType(arms) = &mut instr {
+                for _ in 0..operand {
+                    let len = r.u32()? as usize;
+                    let name = r.take(len)?;
+                    let name = Value::TYPE_NAMES.iter().find(|n| n.as_bytes() == name)
+                        .ok_or("Unknown type name")?;
+                    arms.push((*name, r.u16()?));
+                }
+            }
+            chk.instrs.push(instr);
+        }
+
+        for _ in 0..r.u32()? {
+            let val = match r.u8()? {
+                0 => Value::Null,
+                1 => Value::Bool(r.u8()? != 0),
+                2 => Value::Number(f64::from_le_bytes(r.array()?)),
+                4 => Value::Int(i64::from_le_bytes(r.array()?)),
+                3 => {
+                    let len = r.u32()? as usize;
+                    let s = std::str::from_utf8(r.take(len)?).map_err(|_| "Invalid string constant")?;
+                    Value::String(s.to_string())
+                },
+                _ => return Err("Unknown constant type")
+            };
+            let key = ConstKey::of(&val);
+            chk.push_const(val, key);
+        }
+
+        for _ in 0..r.u32()? {
+            let line = r.u32()?;
+            let repeat = r.u16()?;
+            chk.lines.push(LineRL {line, repeat});
+        }
+
+        if r.pos != bytes.len() {
+            return Err("Trailing bytes after the chunk");
+        }
+
+        // the VM takes both of these on trust, so reject files that break them here
+        let covered: usize = chk.lines.iter().map(|l| l.repeat as usize).sum();
+        if covered < chk.instrs.len() {
+            return Err("Line table doesn't cover the instructions");
+        }
+        for instr in chk.instrs.iter() {
+            let const_id = match instr {
+                Instruction::PushConstant(id) | Instruction::PushSymbol(id)
+                | Instruction::TraceLabeled(id) => u32::from(*id),
+                Instruction::PushConstantLong(id) => *id,
+                _ => continue
+            };
+            if const_id as usize >= chk.consts.len() {
+                return Err("Constant id out of range");
+            }
+        }
+        Ok(chk)
+    }
+
     pub fn print_instr_info(&self, instr: &Instruction) {
         match instr {
-            Instruction::PushConstant (id) => println!("{:?}, value: {:?}", instr, self.get_const(*id)),
+            Instruction::PushConstant (id) => println!("{:?}, value: {}", instr, self.const_info(u32::from(*id))),
+            Instruction::PushConstantLong (id) => println!("{:?}, value: {}", instr, self.const_info(*id)),
+            Instruction::TraceLabeled (id) => println!("{:?}, label: {}", instr, self.const_info(u32::from(*id))),
             _ => println!("{:?}", instr)
         };
     }
 }
 
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len())
+            .ok_or("Unexpected end of bytecode")?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], &'static str> {
+        let mut arr = [0; N];
+        arr.copy_from_slice(self.take(N)?);
+        Ok(arr)
+    }
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -132,4 +440,145 @@ mod tests {
         assert_eq!(chk.get_instr(2).clone(), Instruction::Multiply);
         assert_eq!(chk.get_line_no(2), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_line_runs() {
+        let mut chk = Chunk::new();
+        for _ in 0..1000 {
+            chk.add_instr(Instruction::Nop, 1);
+        }
+        for _ in 0..3 {
+            chk.add_instr(Instruction::Nop, 2);
+        }
+
+        assert_eq!(chk.lines.len(), 2);
+        assert_eq!(chk.get_line_no(0), 1);
+        assert_eq!(chk.get_line_no(999), 1);
+        assert_eq!(chk.get_line_no(1000), 2);
+        assert_eq!(chk.get_line_no(1002), 2);
+        assert_eq!(chk.get_last_line(), 2);
+
+        //a run longer than u16::MAX is split in two
+        let mut chk = Chunk::new();
+        for _ in 0..=u16::MAX as u32 {
+            chk.add_instr(Instruction::Nop, 7);
+        }
+        assert_eq!(chk.lines.len(), 2);
+        assert_eq!(chk.get_line_no(u16::MAX as u32 - 1), 7);
+        assert_eq!(chk.get_line_no(u16::MAX as u32), 7);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut a = Chunk::new();
+        a.add_const(Value::Number(1.));
+        a.add_instr(Instruction::PushConstant(0), 1);
+        a.add_instr(Instruction::Negate, 1);
+        a.add_instr(Instruction::Return, 1);
+
+        let b = a.clone();
+        assert!(a == b);
+        assert!(a.diff(&b).is_empty());
+
+        let mut c = Chunk::new();
+        c.add_const(Value::Number(1.));
+        c.add_instr(Instruction::PushConstant(0), 1);
+        c.add_instr(Instruction::Not, 1);
+        c.add_instr(Instruction::Return, 1);
+
+        assert!(a != c);
+        assert_eq!(a.diff(&c), vec!["instruction 1: Some(Negate) != Some(Not) (after PushConstant(0))"]);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut chk = Chunk::new();
+
+        //5 - 5 / 2.5 + 1 * 2, as in the vm's test_arithmetic
+        chk.add_push_const(Value::Number(5.), 0);
+        chk.add_push_const(Value::Number(5.), 0);
+        chk.add_push_const(Value::Number(2.5), 0);
+        chk.add_instr(Instruction::Divide, 0);
+        chk.add_instr(Instruction::Subtract, 0);
+        chk.add_push_const(Value::Number(1.), 1);
+        chk.add_push_const(Value::Number(2.), 1);
+        chk.add_instr(Instruction::Multiply, 1);
+        chk.add_instr(Instruction::Add, 1);
+        chk.add_instr(Instruction::JumpIfFalse(1), 2);
+        chk.add_instr(Instruction::Return, 2);
+        chk.add_instr(Instruction::PushConstant(9), 2);
+
+        let text = chk.disassemble();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[1], "0001    0 PushConstant     0 (Number(5.0))");
+        assert_eq!(lines[2], "0002    0 PushConstant     1 (Number(2.5))");
+        assert_eq!(lines[3], "0003    0 Divide");
+        assert_eq!(lines[8], "0008    1 Add");
+        assert_eq!(lines[9], "0009    2 JumpIfFalse      1 (to 0011)");
+        assert_eq!(lines[11], "0011    2 PushConstant     9 (<out of range>)");
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut chk = Chunk::new();
+        chk.add_const(Value::Number(1.5));
+        chk.add_const(Value::String("🧀 queso".to_string()));
+        chk.add_const(Value::Bool(true));
+        chk.add_const(Value::Null);
+        chk.add_const(Value::Int(-42));
+        chk.add_instr(Instruction::PushConstant(0), 1);
+        chk.add_instr(Instruction::PushConstant(1), 1);
+        chk.add_instr(Instruction::Add, 2);
+        chk.add_instr(Instruction::Trace, 2);
+        chk.add_instr(Instruction::SwitchType(vec![("number", 1), ("null", 2)]), 3);
+        chk.add_instr(Instruction::Return, 3);
+
+        let bytes = chk.serialize().expect("plain constants serialize");
+        let loaded = Chunk::deserialize(&bytes).expect("round trip failed");
+        assert!(chk.diff(&loaded).is_empty());
+        assert!(chk == loaded);
+
+        assert_eq!(Chunk::deserialize(&bytes[..bytes.len() - 1]).err(), Some("Unexpected end of bytecode"));
+        assert_eq!(Chunk::deserialize(b"nope").err(), Some("Not a queso bytecode file"));
+
+        let mut truncated = chk.clone();
+        truncated.lines.pop();
+        assert_eq!(Chunk::deserialize(&truncated.serialize().unwrap()).err(), Some("Line table doesn't cover the instructions"));
+
+        let mut bad_const = chk.clone();
+        bad_const.add_instr(Instruction::PushConstantLong(5), 3);
+        assert_eq!(Chunk::deserialize(&bad_const.serialize().unwrap()).err(), Some("Constant id out of range"));
+        bad_const.set_instr(6, Instruction::TraceLabeled(9));
+        assert_eq!(Chunk::deserialize(&bad_const.serialize().unwrap()).err(), Some("Constant id out of range"));
+
+        let mut symbol = Chunk::new();
+        symbol.add_const(Value::Symbol(Symbol::new("x")));
+        assert_eq!(symbol.serialize().err(), Some("Only plain values can be serialized as constants"));
+    }
+
+    #[test]
+    fn test_wide_consts() {
+        let mut chk = Chunk::new();
+        for i in 0..=u16::MAX as u32 + 1 {
+            chk.add_push_const(Value::Number(i as f64), 0);
+        }
+
+        assert_eq!(chk.get_instr(u16::MAX as usize).clone(), Instruction::PushConstant(u16::MAX));
+        assert_eq!(chk.get_instr(u16::MAX as usize + 1).clone(), Instruction::PushConstantLong(u16::MAX as u32 + 1));
+        assert_eq!(chk.get_line_no(u16::MAX as u32 + 1), 0);
+    }
+
+    #[test]
+    fn test_const_dedup() {
+        let mut chk = Chunk::new();
+        assert_eq!(chk.add_const(Value::Number(5.)), 0);
+        assert_eq!(chk.add_const(Value::String("5".to_string())), 1);
+        assert_eq!(chk.add_const(Value::Number(5.)), 0);
+        assert_eq!(chk.add_const(Value::String("5".to_string())), 1);
+        assert_eq!(chk.add_const(Value::Number(-0.)), 2);
+        assert_eq!(chk.add_const(Value::Number(0.)), 3);
+        assert_eq!(chk.consts.len(), 4);
+    }
+}
@@ -20,17 +20,28 @@ impl<'a> Compiler<'a> {
     fn label_jump(&mut self, jump_id: usize) {
         self.chk.set_instr(jump_id, Instruction::Jump((self.chk.instrs.len() - 1 - jump_id) as u16));
     }
+    fn label_short_circuit(&mut self, jump_id: usize, op: TokenType) {
+        let offset = (self.chk.instrs.len() - 1 - jump_id) as u16;
+        let instr = if op == TokenType::And {Instruction::And(offset)} else {Instruction::Or(offset)};
+        self.chk.set_instr(jump_id, instr);
+    }
     pub fn compile(&mut self, program: Program) {
         for stmt in program {
             self.compile_stmt(stmt);
         };
-        self.chk.add_instr(Instruction::Return, 0);
+        self.chk.add_instr(Instruction::Halt, 0);
     }
     fn compile_expr(&mut self, expr: Expr) {
         match expr {
             Expr::Constant(tok) => {
-                let const_id = self.chk.add_const(Value::from(&tok));
-                self.chk.add_instr(Instruction::PushConstant(const_id), tok.pos.line);
+                self.chk.add_push_const(Value::from(&tok), tok.pos.line);
+            },
+            Expr::Binary(left, op, right) if op.t == TokenType::And || op.t == TokenType::Or => {
+                //the right side is only evaluated when the left one doesn't decide the result
+                self.compile_expr(*left);
+                let jump = self.make_jump();
+                self.compile_expr(*right);
+                self.label_short_circuit(jump, op.t);
             },
             Expr::Binary(left, op, right) => {
                 self.compile_expr(*left);
@@ -40,7 +51,8 @@ impl<'a> Compiler<'a> {
                     TokenType::Minus => self.chk.add_instr(Instruction::Subtract, op.pos.line),
                     TokenType::Star  => self.chk.add_instr(Instruction::Multiply, op.pos.line),
                     TokenType::Slash => self.chk.add_instr(Instruction::Divide, op.pos.line),
-                    TokenType::Slash => self.chk.add_instr(Instruction::Divide, op.pos.line),
+                    TokenType::Percent => self.chk.add_instr(Instruction::Modulo, op.pos.line),
+                    TokenType::StarStar => self.chk.add_instr(Instruction::Exponent, op.pos.line),
 
                     TokenType::EqualEqual   => self.chk.add_instr(Instruction::Equal, op.pos.line),
                     TokenType::BangEqual    => self.chk.add_instr(Instruction::NotEqual, op.pos.line),
@@ -174,6 +186,43 @@ mod tests {
         assert_eq!(chk.get_instr(1).clone(), Instruction::PushConstant(1));
         assert_eq!(chk.get_instr(2).clone(), Instruction::Multiply);
         assert_eq!(chk.get_instr(3).clone(), Instruction::Pop);
-        assert_eq!(chk.get_instr(4).clone(), Instruction::Return);
+        assert_eq!(chk.get_instr(4).clone(), Instruction::Halt);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let mut chk = Chunk::new();
+        let tok = |t: TokenType, val: &str| Token {
+            pos: TokenPos {from_col: 0, to_col: 1, line: 1},
+            t,
+            val: val.to_string()
+        };
+        let left = Expr::FalseLiteral(tok(TokenType::False, "false"));
+        let right = Expr::Constant(tok(TokenType::Number, "2"));
+        let expr = Expr::Binary(Box::new(left), tok(TokenType::And, "&"), Box::new(right));
+        let program = vec![Stmt::Expr(Box::new(expr))];
+
+        let mut compiler = Compiler::new(&mut chk);
+        compiler.compile(program);
+
+        assert_eq!(chk.get_instr(0).clone(), Instruction::PushFalse);
+        assert_eq!(chk.get_instr(1).clone(), Instruction::And(1));
+        assert_eq!(chk.get_instr(2).clone(), Instruction::PushConstant(0));
+        assert_eq!(chk.get_instr(3).clone(), Instruction::Pop);
+    }
+
+    #[test]
+    fn test_short_circuit_source() {
+        let src = "trace false & 2;\ntrace 1 & 2;\ntrace false || 3;\ntrace 4 || 5;";
+        let mut parser = Parser::new(TokenStream::new(Lexer::new(src.to_string())));
+        let program = parser.program();
+        assert!(!parser.had_error);
+        let program = Resolver::new().resolve(program).expect("the program resolves");
+
+        let mut chk = Chunk::new();
+        Compiler::new(&mut chk).compile(program);
+
+        let (_, out) = VM::new(false).run_capturing(chk).expect("the program runs");
+        assert_eq!(out, "[1] false\n[2] 2\n[3] 3\n[4] 4\n");
     }
 }
\ No newline at end of file
@@ -13,7 +13,7 @@ impl Env {
         }
     }
     pub fn add(&mut self, name: Token) {
-        self.locals.push(Local {name, depth: self.scope_depth as u8});
+        self.locals.push(Local {name, depth: self.scope_depth});
     }
     pub fn get(&self, id: usize) -> &Local {
         self.locals.get(id).expect("This is a problem with the compiler itself")
@@ -23,7 +23,7 @@ impl Env {
     }
     pub fn close(&mut self) -> u32 {
         let mut pop_count = 0;
-        while self.locals.len()!=0 {
+        while !self.locals.is_empty() {
             if self.locals.last().expect("This is a problem with the compiler itself").depth == self.scope_depth {
                 self.locals.pop();
                 pop_count +=1;
@@ -35,7 +35,7 @@ impl Env {
     }
 
     pub fn is_redefined(&self, other: &Token) -> bool {
-        if self.locals.len() == 0 {return false}
+        if self.locals.is_empty() {return false}
         let mut i = self.locals.len() - 1;
         loop {
             let local = self.locals.get(i).expect("This is a problem with the compiler itself");
@@ -45,10 +45,10 @@ impl Env {
                 return true;
             }
 
-            if i <= 0 {break;}
+            if i == 0 {break;}
             i -= 1;
         }
-        return false
+        false
     }
 }
 
@@ -9,4 +9,23 @@ pub fn error(at: Token, msg: &'static str) {
     }
 }
 
-pub fn runtime_error() {}
\ No newline at end of file
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: u32
+}
+
+impl RuntimeError {
+    pub fn new(message: &str, line: u32) -> RuntimeError {
+        RuntimeError {
+            message: message.to_string(),
+            line
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.line, self.message)
+    }
+}
\ No newline at end of file
@@ -1,19 +1,37 @@
 use crate::*;
+use std::convert::TryFrom;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     PushConstant (u16),
+    // for constant ids past u16::MAX
+    PushConstantLong (u32),
     PushTrue,
     PushFalse,
     PushNull,
+    // interns the string constant and pushes it as a symbol
+    PushSymbol(u16),
 
     Negate,
     ToNumber,
+    // pushes the name of the type of the popped value
+    GetType,
     Not,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Exponent,
+    // integers only, shifts by 0 to 63
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    // integer-valued numbers only, Log2 errors on anything below 1
+    IsPowerOfTwo,
+    Log2,
 
     Equal,
     NotEqual,
@@ -21,14 +39,500 @@ pub enum Instruction {
     LessEqual,
     Greater,
     Less,
+    // x lo hi -> lo <= x <= hi
+    InRange,
+    // a b epsilon -> |a - b| <= epsilon, a null epsilon means DEFAULT_EPSILON
+    ApproxEq,
+    // actual expected message -> null, errors with both values and the
+    // message unless actual and expected are equal
+    AssertEq,
 
     Trace,
+    TraceLabeled(u16),
+
+    // string indices count unicode scalar values
+    StrLen,
+    Substring,
+    CharAt,
+    // template arg0 .. argn-1 -> string, {i} is replaced by argi and {{ and
+    // }} are literal braces
+    FormatStr(u16),
+    // SbAppend appends the popped value to the builder below it
+    SbNew,
+    SbAppend,
+    SbFinish,
+    // NewArray pops n values into a new array. Indices can be negative like
+    // string indices, IndexSet keeps the stored value on top
+    NewArray(u16),
+    IndexGet,
+    IndexSet,
+    // array start end replacement -> replacement, splices the replacement's
+    // elements over array[start..end], null bounds mean the array's ends
+    SliceSet,
+
+    // NewMap pops n key value pairs into a new map, MapGet pushes null for
+    // a missing key, MapSet keeps the stored value on top
+    NewMap(u16),
+    MapGet,
+    MapSet,
+    // empties the popped array or map in place and pushes null
+    Clear,
 
     PushVariable(u16),
     Assign(u16),
+    // add or subtract 1 from the number in the slot, nothing is pushed
+    Increment(u16),
+    Decrement(u16),
+    // globals aren't on the stack, SetGlobal keeps the value on top like Assign
+    GetGlobal(u16),
+    SetGlobal(u16),
 
     JumpIfFalse(u16),
     Jump(u16),
+    // jumps backwards
+    Loop(u16),
+    // short-circuit: if the left operand on top decides the result it's kept
+    // and the right operand skipped, otherwise it's popped and evaluation
+    // falls through to the right operand
+    And(u16),
+    Or(u16),
+    // jumps forward by the offset of the arm named after the type_name of the
+    // value on top, which stays on the stack. Falls through if no arm matches
+    SwitchType(Vec<(&'static str, u16)>),
     JumpPlaceholder,
-    Pop, Return
-}
\ No newline at end of file
+    Pop, Return,
+    // ends the program, with the value on top as its result
+    Halt,
+    Dup, Swap,
+    // duplicates the top n values, keeping their order
+    DupN(u16),
+
+    // errors jump forward by the offset with the error message on the
+    // stack, until the handler is popped
+    PushTryHandler(u16),
+    PopTryHandler,
+
+    // does nothing, lets optimizations blank out instructions without shifting jump offsets
+    Nop
+}
+
+// no wildcard arms below on purpose, so a new variant has to be added here too
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::PushConstant(_) => "PushConstant",
+            Instruction::PushConstantLong(_) => "PushConstantLong",
+            Instruction::PushSymbol(_) => "PushSymbol",
+            Instruction::PushTrue => "PushTrue",
+            Instruction::PushFalse => "PushFalse",
+            Instruction::PushNull => "PushNull",
+
+            Instruction::Negate => "Negate",
+            Instruction::ToNumber => "ToNumber",
+            Instruction::Not => "Not",
+            Instruction::Add => "Add",
+            Instruction::Subtract => "Subtract",
+            Instruction::Multiply => "Multiply",
+            Instruction::Divide => "Divide",
+            Instruction::Modulo => "Modulo",
+            Instruction::Exponent => "Exponent",
+            Instruction::BitAnd => "BitAnd",
+            Instruction::BitOr => "BitOr",
+            Instruction::BitXor => "BitXor",
+            Instruction::Shl => "Shl",
+            Instruction::Shr => "Shr",
+
+            Instruction::Equal => "Equal",
+            Instruction::NotEqual => "NotEqual",
+            Instruction::GreaterEqual => "GreaterEqual",
+            Instruction::LessEqual => "LessEqual",
+            Instruction::Greater => "Greater",
+            Instruction::Less => "Less",
+            Instruction::InRange => "InRange",
+
+            Instruction::Trace => "Trace",
+            Instruction::TraceLabeled(_) => "TraceLabeled",
+
+            Instruction::StrLen => "StrLen",
+            Instruction::Substring => "Substring",
+            Instruction::CharAt => "CharAt",
+            Instruction::SbNew => "SbNew",
+            Instruction::SbAppend => "SbAppend",
+            Instruction::SbFinish => "SbFinish",
+
+            Instruction::PushVariable(_) => "PushVariable",
+            Instruction::Assign(_) => "Assign",
+            Instruction::Increment(_) => "Increment",
+            Instruction::Decrement(_) => "Decrement",
+            Instruction::GetGlobal(_) => "GetGlobal",
+            Instruction::SetGlobal(_) => "SetGlobal",
+
+            Instruction::JumpIfFalse(_) => "JumpIfFalse",
+            Instruction::Jump(_) => "Jump",
+            Instruction::Loop(_) => "Loop",
+            Instruction::And(_) => "And",
+            Instruction::Or(_) => "Or",
+            Instruction::SwitchType(_) => "SwitchType",
+            Instruction::JumpPlaceholder => "JumpPlaceholder",
+            Instruction::Pop => "Pop",
+            Instruction::Return => "Return",
+            Instruction::Halt => "Halt",
+            Instruction::Dup => "Dup",
+            Instruction::Swap => "Swap",
+            Instruction::DupN(_) => "DupN",
+            Instruction::PushTryHandler(_) => "PushTryHandler",
+            Instruction::PopTryHandler => "PopTryHandler",
+            Instruction::NewArray(_) => "NewArray",
+            Instruction::IndexGet => "IndexGet",
+            Instruction::IndexSet => "IndexSet",
+            Instruction::NewMap(_) => "NewMap",
+            Instruction::MapGet => "MapGet",
+            Instruction::MapSet => "MapSet",
+            Instruction::SliceSet => "SliceSet",
+            Instruction::Clear => "Clear",
+            Instruction::IsPowerOfTwo => "IsPowerOfTwo",
+            Instruction::Log2 => "Log2",
+            Instruction::ApproxEq => "ApproxEq",
+            Instruction::AssertEq => "AssertEq",
+            Instruction::FormatStr(_) => "FormatStr",
+            Instruction::Nop => "Nop",
+            Instruction::GetType => "GetType"
+        }
+    }
+
+    // opcode and operand used by the bytecode format, see Chunk::serialize
+    // opcodes are stored in .qbc files, so new instructions get new numbers at the end
+    pub fn encode(&self) -> (u8, Option<u32>) {
+        match self {
+            Instruction::PushConstant(arg) => (0, Some(u32::from(*arg))),
+            Instruction::PushTrue => (1, None),
+            Instruction::PushFalse => (2, None),
+            Instruction::PushNull => (3, None),
+            Instruction::Negate => (4, None),
+            Instruction::ToNumber => (5, None),
+            Instruction::Not => (6, None),
+            Instruction::Add => (7, None),
+            Instruction::Subtract => (8, None),
+            Instruction::Multiply => (9, None),
+            Instruction::Divide => (10, None),
+            Instruction::Modulo => (11, None),
+            Instruction::Exponent => (12, None),
+            Instruction::Equal => (13, None),
+            Instruction::NotEqual => (14, None),
+            Instruction::GreaterEqual => (15, None),
+            Instruction::LessEqual => (16, None),
+            Instruction::Greater => (17, None),
+            Instruction::Less => (18, None),
+            Instruction::InRange => (19, None),
+            Instruction::Trace => (20, None),
+            Instruction::TraceLabeled(arg) => (21, Some(u32::from(*arg))),
+            Instruction::StrLen => (22, None),
+            Instruction::Substring => (23, None),
+            Instruction::CharAt => (24, None),
+            Instruction::PushVariable(arg) => (25, Some(u32::from(*arg))),
+            Instruction::Assign(arg) => (26, Some(u32::from(*arg))),
+            Instruction::JumpIfFalse(arg) => (27, Some(u32::from(*arg))),
+            Instruction::Jump(arg) => (28, Some(u32::from(*arg))),
+            Instruction::JumpPlaceholder => (29, None),
+            Instruction::Pop => (30, None),
+            Instruction::Return => (31, None),
+            Instruction::Nop => (32, None),
+            Instruction::PushConstantLong(arg) => (33, Some(*arg)),
+            Instruction::Loop(arg) => (34, Some(u32::from(*arg))),
+            Instruction::Dup => (35, None),
+            Instruction::Swap => (36, None),
+            Instruction::GetType => (37, None),
+            Instruction::And(arg) => (38, Some(u32::from(*arg))),
+            Instruction::Or(arg) => (39, Some(u32::from(*arg))),
+            // only the arm count, the arms follow in the .qbc format, see Chunk::serialize
+            Instruction::SwitchType(arms) => (40, Some(arms.len() as u32)),
+            Instruction::PushSymbol(arg) => (41, Some(u32::from(*arg))),
+            Instruction::DupN(arg) => (42, Some(u32::from(*arg))),
+            Instruction::PushTryHandler(arg) => (43, Some(u32::from(*arg))),
+            Instruction::PopTryHandler => (44, None),
+            Instruction::Increment(arg) => (45, Some(u32::from(*arg))),
+            Instruction::Decrement(arg) => (46, Some(u32::from(*arg))),
+            Instruction::SbNew => (47, None),
+            Instruction::SbAppend => (48, None),
+            Instruction::SbFinish => (49, None),
+            Instruction::GetGlobal(arg) => (50, Some(u32::from(*arg))),
+            Instruction::SetGlobal(arg) => (51, Some(u32::from(*arg))),
+            Instruction::Halt => (52, None),
+            Instruction::BitAnd => (53, None),
+            Instruction::BitOr => (54, None),
+            Instruction::BitXor => (55, None),
+            Instruction::Shl => (56, None),
+            Instruction::Shr => (57, None),
+            Instruction::NewArray(arg) => (58, Some(u32::from(*arg))),
+            Instruction::IndexGet => (59, None),
+            Instruction::IndexSet => (60, None),
+            Instruction::NewMap(arg) => (61, Some(u32::from(*arg))),
+            Instruction::MapGet => (62, None),
+            Instruction::MapSet => (63, None),
+            Instruction::SliceSet => (64, None),
+            Instruction::Clear => (65, None),
+            Instruction::IsPowerOfTwo => (66, None),
+            Instruction::Log2 => (67, None),
+            Instruction::ApproxEq => (68, None),
+            Instruction::AssertEq => (69, None),
+            Instruction::FormatStr(arg) => (70, Some(u32::from(*arg))),
+        }
+    }
+
+    pub fn decode(opcode: u8, operand: u32) -> Option<Instruction> {
+        let narrow = u16::try_from(operand).ok();
+        Some(match opcode {
+            0 => Instruction::PushConstant(narrow?),
+            1 => Instruction::PushTrue,
+            2 => Instruction::PushFalse,
+            3 => Instruction::PushNull,
+            4 => Instruction::Negate,
+            5 => Instruction::ToNumber,
+            6 => Instruction::Not,
+            7 => Instruction::Add,
+            8 => Instruction::Subtract,
+            9 => Instruction::Multiply,
+            10 => Instruction::Divide,
+            11 => Instruction::Modulo,
+            12 => Instruction::Exponent,
+            13 => Instruction::Equal,
+            14 => Instruction::NotEqual,
+            15 => Instruction::GreaterEqual,
+            16 => Instruction::LessEqual,
+            17 => Instruction::Greater,
+            18 => Instruction::Less,
+            19 => Instruction::InRange,
+            20 => Instruction::Trace,
+            21 => Instruction::TraceLabeled(narrow?),
+            22 => Instruction::StrLen,
+            23 => Instruction::Substring,
+            24 => Instruction::CharAt,
+            25 => Instruction::PushVariable(narrow?),
+            26 => Instruction::Assign(narrow?),
+            27 => Instruction::JumpIfFalse(narrow?),
+            28 => Instruction::Jump(narrow?),
+            29 => Instruction::JumpPlaceholder,
+            30 => Instruction::Pop,
+            31 => Instruction::Return,
+            32 => Instruction::Nop,
+            33 => Instruction::PushConstantLong(operand),
+            34 => Instruction::Loop(narrow?),
+            35 => Instruction::Dup,
+            36 => Instruction::Swap,
+            37 => Instruction::GetType,
+            38 => Instruction::And(narrow?),
+            39 => Instruction::Or(narrow?),
+            40 => Instruction::SwitchType(Vec::new()),
+            41 => Instruction::PushSymbol(narrow?),
+            42 => Instruction::DupN(narrow?),
+            43 => Instruction::PushTryHandler(narrow?),
+            44 => Instruction::PopTryHandler,
+            45 => Instruction::Increment(narrow?),
+            46 => Instruction::Decrement(narrow?),
+            47 => Instruction::SbNew,
+            48 => Instruction::SbAppend,
+            49 => Instruction::SbFinish,
+            50 => Instruction::GetGlobal(narrow?),
+            51 => Instruction::SetGlobal(narrow?),
+            52 => Instruction::Halt,
+            53 => Instruction::BitAnd,
+            54 => Instruction::BitOr,
+            55 => Instruction::BitXor,
+            56 => Instruction::Shl,
+            57 => Instruction::Shr,
+            58 => Instruction::NewArray(narrow?),
+            59 => Instruction::IndexGet,
+            60 => Instruction::IndexSet,
+            61 => Instruction::NewMap(narrow?),
+            62 => Instruction::MapGet,
+            63 => Instruction::MapSet,
+            64 => Instruction::SliceSet,
+            65 => Instruction::Clear,
+            66 => Instruction::IsPowerOfTwo,
+            67 => Instruction::Log2,
+            68 => Instruction::ApproxEq,
+            69 => Instruction::AssertEq,
+            70 => Instruction::FormatStr(narrow?),
+            _ => return None
+        })
+    }
+
+    pub fn operand_count(&self) -> usize {
+        match self {
+            Instruction::PushConstant(_)
+            | Instruction::PushConstantLong(_)
+            | Instruction::PushSymbol(_)
+            | Instruction::TraceLabeled(_)
+            | Instruction::PushVariable(_)
+            | Instruction::Assign(_)
+            | Instruction::Increment(_)
+            | Instruction::Decrement(_)
+            | Instruction::GetGlobal(_)
+            | Instruction::SetGlobal(_)
+            | Instruction::JumpIfFalse(_)
+            | Instruction::Jump(_)
+            | Instruction::Loop(_)
+            | Instruction::And(_)
+            | Instruction::Or(_)
+            | Instruction::SwitchType(_)
+            | Instruction::DupN(_)
+            | Instruction::NewArray(_)
+            | Instruction::NewMap(_)
+            | Instruction::FormatStr(_)
+            | Instruction::PushTryHandler(_) => 1,
+
+            Instruction::PushTrue
+            | Instruction::PushFalse
+            | Instruction::PushNull
+            | Instruction::Negate
+            | Instruction::ToNumber
+            | Instruction::GetType
+            | Instruction::Not
+            | Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Modulo
+            | Instruction::Exponent
+            | Instruction::BitAnd
+            | Instruction::BitOr
+            | Instruction::BitXor
+            | Instruction::Shl
+            | Instruction::Shr
+            | Instruction::Equal
+            | Instruction::NotEqual
+            | Instruction::GreaterEqual
+            | Instruction::LessEqual
+            | Instruction::Greater
+            | Instruction::Less
+            | Instruction::InRange
+            | Instruction::Trace
+            | Instruction::StrLen
+            | Instruction::Substring
+            | Instruction::CharAt
+            | Instruction::SbNew
+            | Instruction::SbAppend
+            | Instruction::SbFinish
+            | Instruction::JumpPlaceholder
+            | Instruction::Pop
+            | Instruction::Return
+            | Instruction::Halt
+            | Instruction::Dup
+            | Instruction::Swap
+            | Instruction::PopTryHandler
+            | Instruction::IndexGet
+            | Instruction::IndexSet
+            | Instruction::MapGet
+            | Instruction::MapSet
+            | Instruction::SliceSet
+            | Instruction::Clear
+            | Instruction::IsPowerOfTwo
+            | Instruction::Log2
+            | Instruction::ApproxEq
+            | Instruction::AssertEq
+            | Instruction::Nop => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let all = vec![
+            Instruction::PushConstant(7),
+            Instruction::PushConstantLong(70000),
+            Instruction::PushTrue,
+            Instruction::PushFalse,
+            Instruction::PushNull,
+            Instruction::Negate,
+            Instruction::ToNumber,
+            Instruction::Not,
+            Instruction::Add,
+            Instruction::Subtract,
+            Instruction::Multiply,
+            Instruction::Divide,
+            Instruction::Modulo,
+            Instruction::Exponent,
+            Instruction::Equal,
+            Instruction::NotEqual,
+            Instruction::GreaterEqual,
+            Instruction::LessEqual,
+            Instruction::Greater,
+            Instruction::Less,
+            Instruction::InRange,
+            Instruction::Trace,
+            Instruction::TraceLabeled(7),
+            Instruction::StrLen,
+            Instruction::Substring,
+            Instruction::CharAt,
+            Instruction::PushVariable(7),
+            Instruction::Assign(7),
+            Instruction::JumpIfFalse(7),
+            Instruction::Jump(7),
+            Instruction::Loop(7),
+            Instruction::And(7),
+            Instruction::Or(7),
+            Instruction::SwitchType(Vec::new()),
+            Instruction::PushSymbol(7),
+            Instruction::DupN(7),
+            Instruction::PushTryHandler(7),
+            Instruction::PopTryHandler,
+            Instruction::Increment(7),
+            Instruction::Decrement(7),
+            Instruction::SbNew,
+            Instruction::SbAppend,
+            Instruction::SbFinish,
+            Instruction::GetGlobal(7),
+            Instruction::SetGlobal(7),
+            Instruction::JumpPlaceholder,
+            Instruction::Pop,
+            Instruction::Return,
+            Instruction::Halt,
+            Instruction::BitAnd,
+            Instruction::BitOr,
+            Instruction::BitXor,
+            Instruction::Shl,
+            Instruction::Shr,
+            Instruction::NewArray(7),
+            Instruction::IndexGet,
+            Instruction::IndexSet,
+            Instruction::NewMap(7),
+            Instruction::MapGet,
+            Instruction::MapSet,
+            Instruction::SliceSet,
+            Instruction::Clear,
+            Instruction::IsPowerOfTwo,
+            Instruction::Log2,
+            Instruction::ApproxEq,
+            Instruction::AssertEq,
+            Instruction::FormatStr(7),
+            Instruction::Dup,
+            Instruction::Swap,
+            Instruction::Nop,
+            Instruction::GetType,
+        ];
+        for instr in all {
+            let (opcode, operand) = instr.encode();
+            assert_eq!(operand.is_some(), instr.operand_count() == 1);
+            assert_eq!(Instruction::decode(opcode, operand.unwrap_or(0)), Some(instr));
+        }
+        assert_eq!(Instruction::decode(255, 0), None);
+        assert_eq!(Instruction::decode(Instruction::PushConstant(0).encode().0, 70000), None);
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(Instruction::PushConstant(3).mnemonic(), "PushConstant");
+        assert_eq!(Instruction::Add.mnemonic(), "Add");
+        assert_eq!(Instruction::JumpIfFalse(2).mnemonic(), "JumpIfFalse");
+        assert_eq!(Instruction::Return.mnemonic(), "Return");
+
+        assert_eq!(Instruction::PushConstant(3).operand_count(), 1);
+        assert_eq!(Instruction::Assign(0).operand_count(), 1);
+        assert_eq!(Instruction::Add.operand_count(), 0);
+        assert_eq!(Instruction::Pop.operand_count(), 0);
+    }
+}
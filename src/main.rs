@@ -43,7 +43,7 @@ use clap::{App, Arg, crate_version};
 
 #[derive(Clone)]
 struct DebugOpts {
-    pub tokens: bool, pub ast: bool, pub instrs: bool
+    pub tokens: bool, pub ast: bool, pub instrs: bool, pub json: bool
 }
 
 #[derive(Clone)]
@@ -79,13 +79,20 @@ fn main() {
             .help("turns on bytecode instructions logging")
             .hidden(true)
         )
+        .arg(
+            Arg::with_name("debug json")
+            .long("#json")
+            .help("turns on bytecode instructions logging as JSON lines")
+            .hidden(true)
+        )
        .get_matches();
 
 
     let debug_opts = DebugOpts {
         tokens: matches.occurrences_of("debug tokens") > 0,
         ast: matches.occurrences_of("debug ast") > 0,
-        instrs: matches.occurrences_of("debug instrs") > 0
+        instrs: matches.occurrences_of("debug instrs") > 0,
+        json: matches.occurrences_of("debug json") > 0
     };
 
     let opts = QuesoOpts {
@@ -105,7 +112,7 @@ fn repl(opts: QuesoOpts) {
         print!(">");
         io::Write::flush(&mut io::stdout()).expect("flush failed!");
         let mut buf = String::new();
-        if let Ok(_) = io::stdin().read_line(&mut buf) {
+        if io::stdin().read_line(&mut buf).is_ok() {
             run(opts.clone(), buf);
         }
         println!();
@@ -144,11 +151,13 @@ fn run(opts: QuesoOpts, src: String) -> bool {
         let mut compiler = Compiler::new(&mut chk);
         compiler.compile(program);
 
-        let mut vm = VM::new(opts.debug.instrs);
+        let mut vm = VM::new(opts.debug.instrs || opts.debug.json);
+        if opts.debug.json {
+            vm.set_trace_format(TraceFormat::Json);
+        }
         let res = vm.execute(chk);
-        match res {
-            Err(err) => println!("{}", err),
-            _ => {}
+        if let Err(err) = res {
+            println!("{}", err);
         }
 
         return true;
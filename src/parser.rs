@@ -64,6 +64,12 @@ impl Parser {
         parser.rules.insert(TokenType::Star,
             ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::Multitplication as u8});
 
+        parser.rules.insert(TokenType::Percent,
+            ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::Multitplication as u8});
+
+        parser.rules.insert(TokenType::StarStar,
+            ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::Exponentiation as u8});
+
         parser.rules.insert(TokenType::Bang,
             ParserRule {prefix: Some(Parser::unary),    infix: None,                    bp: BP::Zero as u8});
 
@@ -88,6 +94,12 @@ impl Parser {
         parser.rules.insert(TokenType::BangEqual,
             ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::Equality as u8});
 
+        parser.rules.insert(TokenType::And,
+            ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::And as u8});
+
+        parser.rules.insert(TokenType::Or,
+            ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::Or as u8});
+
         parser.rules.insert(TokenType::Greater,
             ParserRule {prefix: None,                   infix: Some(Parser::binary),    bp: BP::Comparison as u8});
 
@@ -181,7 +193,7 @@ impl Parser {
         let cur = cur.clone();
         // if there is no prefix rule for the current token, emit this error
         self.error(cur, "Expected an expression");
-        return Expr::Error;
+        Expr::Error
     }
 
     pub fn expr(&mut self) -> Expr {
@@ -204,7 +216,8 @@ impl Parser {
         let op = self.toks.next().clone();
         
         let rule = self.get_rule(op.t);
-        let right = self.parse_bp(rule.bp + 1);
+        // ** is right associative, 2 ** 3 ** 2 == 2 ** (3 ** 2)
+        let right = if op.t == TokenType::StarStar {self.parse_bp(rule.bp)} else {self.parse_bp(rule.bp + 1)};
 
         Expr::Binary(Box::new(left), op, Box::new(right))
     }
@@ -266,11 +279,10 @@ impl Parser {
         stmts
     }
     fn stmt(&mut self) -> Stmt {
-        let stmt: Stmt;
-        match self.toks.peek().t {
-            TokenType::Mut => stmt = self.mut_decl(),
-            _ => stmt = self.expr_stmt()
-        }
+        let stmt = match self.toks.peek().t {
+            TokenType::Mut => self.mut_decl(),
+            _ => self.expr_stmt()
+        };
 
         if self.consume(TokenType::Semi, "Expected a SEMI after expression") {
             self.panic = false;
@@ -279,8 +291,7 @@ impl Parser {
         stmt
     }
     fn expr_stmt(&mut self) -> Stmt {
-        let stmt = Stmt::Expr(Box::new(self.expr()));
-        stmt
+        Stmt::Expr(Box::new(self.expr()))
     }
     fn mut_decl(&mut self) -> Stmt {
         self.toks.next();
@@ -299,4 +310,24 @@ impl Parser {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_logical_precedence() {
+        let toks = TokenStream::new(Lexer::new(String::from("false || 1 & 2 == 3;")));
+        let mut parser = Parser::new(toks);
+        let program = parser.program();
+
+        assert!(!parser.had_error);
+        assert_eq!(program[0].to_string(), "(|| false (& 1 (== 2 3)))");
+    }
+
+    #[test]
+    fn test_exponent_precedence() {
+        let toks = TokenStream::new(Lexer::new(String::from("2 ** 3 ** 2 % 5;")));
+        let mut parser = Parser::new(toks);
+        let program = parser.program();
+
+        assert!(!parser.had_error);
+        assert_eq!(program[0].to_string(), "(% (** 2 (** 3 2)) 5)");
+    }
 }
\ No newline at end of file
@@ -20,11 +20,11 @@ impl Resolver {
             }
         }
         if id < 0 {
-            return Err("Usage of an undefined variable");
+            Err("Usage of an undefined variable")
         }
         else {
             let mut id: u32 = id as u32;
-            return Ok(id)
+            Ok(id)
         }
     }
 
@@ -1,5 +1,6 @@
 use crate::*;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TokenType {
     LeftParen, RightParen, LeftBracket, RightBracket, LeftBrace, RightBrace,
@@ -39,7 +40,7 @@ pub struct Token {
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let is_empty = self.val.trim().is_empty();
-        if self.val.len() > 0 && !is_empty{
+        if !self.val.is_empty() && !is_empty{
             write!(f, "{:?}: {}", self.t, self.val)
         }
         else {write!(f, "{:?}", self.t)}
@@ -98,6 +99,6 @@ impl TokenStream {
             self.next();
             return true;
         }
-        return false;
+        false
     }
 }
\ No newline at end of file
@@ -1,61 +1,364 @@
 use crate::*;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Bool(bool),
     Number(f64),
+    Int(i64),
     String(String),
+    Symbol(Symbol),
+    // appended to in place by SbAppend, so building a string in a loop
+    // doesn't copy it on every iteration
+    StringBuilder(Rc<RefCell<String>>),
+    // shared like a string builder, so every copy sees what IndexSet changes
+    Array(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
     Null
 }
 
+/// An interned name, see `VM::intern`. Symbols with the same name share one
+/// allocation, so comparing them is a pointer comparison.
+#[derive(Debug, Clone)]
+pub struct Symbol(Rc<str>);
+
+impl Symbol {
+    pub fn new(name: &str) -> Symbol {
+        Symbol(Rc::from(name))
+    }
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The values that can be map keys: numbers, strings, bools and null. Ints
+/// and whole floats are the same key, so `1` and `1.0` find the same entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Number(u64),
+    String(String)
+}
+
+impl MapKey {
+    pub fn from_value(val: &Value) -> Result<MapKey, &'static str> {
+        match val {
+            Value::Null => Ok(MapKey::Null),
+            Value::Bool(b) => Ok(MapKey::Bool(*b)),
+            Value::Int(i) => Ok(MapKey::Int(*i)),
+            Value::Number(n) if n.fract() == 0. && *n >= i64::MIN as f64 && *n < i64::MAX as f64 =>
+                Ok(MapKey::Int(*n as i64)),
+            //every NaN is the same key, since NaN equals itself
+            Value::Number(n) if n.is_nan() => Ok(MapKey::Number(f64::NAN.to_bits())),
+            Value::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            Value::String(s) => Ok(MapKey::String(s.clone())),
+            _ => Err("Map keys can only be numbers, strings, bools or null")
+        }
+    }
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Null => Value::Null,
+            MapKey::Bool(b) => Value::Bool(*b),
+            MapKey::Int(i) => Value::Int(*i),
+            MapKey::Number(bits) => Value::Number(f64::from_bits(*bits)),
+            MapKey::String(s) => Value::String(s.clone())
+        }
+    }
+}
+
+/// A map that keeps its keys in insertion order, so printing or iterating
+/// it gives the same result on every run. Overwriting a key keeps its place.
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(MapKey, Value)>,
+    index: HashMap<MapKey, usize>
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map::default()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.index.get(key).map(|id| &self.entries[*id].1)
+    }
+    pub fn contains_key(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+    pub fn insert(&mut self, key: MapKey, val: Value) {
+        match self.index.get(&key) {
+            Some(id) => self.entries[*id].1 = val,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, val));
+            }
+        }
+    }
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &MapKey> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(_, val)| val)
+    }
+    pub fn entries(&self) -> impl Iterator<Item = (&MapKey, &Value)> {
+        self.entries.iter().map(|(key, val)| (key, val))
+    }
+}
+
+// the same entries in any order
+impl PartialEq for Map {
+    fn eq(&self, other: &Map) -> bool {
+        self.len() == other.len() && self.entries().all(|(key, val)| other.get(key) == Some(val))
+    }
+}
+
+// is_truthy, is_equal_to and is_greater_than are the semantics the VM uses
+// for conditions and comparisons. Native code should call these instead of
+// matching on values itself.
 impl Value {
+    /// Every name type_name can return.
+    pub const TYPE_NAMES: [&'static str; 8] = ["bool", "number", "string", "symbol", "stringbuilder", "array", "map", "null"];
+
+    /// `false`, `0`, `""` and `null` are falsy, everything else is truthy.
+    ///
+    /// ```ignore
+    /// assert!(!Value::Number(0.).is_truthy());
+    /// assert!(Value::String("queso".to_string()).is_truthy());
+    /// ```
     pub fn is_truthy(&self) -> bool {
         match self {
             Value::Bool(b) => *b,
             Value::Number(n) => *n!=0.,
-            Value::String(s) => s.len() > 0,
+            Value::Int(i) => *i!=0,
+            Value::String(s) => !s.is_empty(),
+            Value::Symbol(_) => true,
+            Value::StringBuilder(_) => true,
+            Value::Array(_) => true,
+            Value::Map(_) => true,
             Value::Null => false
         }
     }
     pub fn to_number(&self) -> Result<f64, &'static str> {
         match self {
-            Value::Number(num) => return Ok(num.clone()),
-            Value::Bool(b) => return Ok(if *b {1.} else {0.}),
-            Value::String(s) => return match s.parse::<f64>() {
-                Ok(num) => return Ok(num),
-                _ => return Err("Could not convert the string to a number")
+            Value::Number(num) => Ok(*num),
+            Value::Int(i) => Ok(*i as f64),
+            Value::Bool(b) => Ok(if *b {1.} else {0.}),
+            Value::String(s) => match s.parse::<f64>() {
+                Ok(num) => Ok(num),
+                _ => Err("Could not convert the string to a number")
             },
-            Value::Null => return Ok(0.),
-            _ => return Err("This operand cannot be converted to a number")
+            Value::Null => Ok(0.),
+            _ => Err("This operand cannot be converted to a number")
         }
     }
     pub fn to_string(&self) -> Result<String, &'static str> {
+        self.to_string_with_precision(None)
+    }
+    // precision is the max number of significant digits numbers are printed
+    // with, None or Some(0) print them in full
+    pub fn to_string_with_precision(&self, precision: Option<usize>) -> Result<String, &'static str> {
         match self {
-            Value::String(s) => return Ok(s.clone()),
-            Value::Bool(b) => return Ok((if *b {"true"} else {"false"}).to_string()),
-            Value::Number(num) => return Ok(num.to_string()),
-            Value::Null => return Ok("null".to_string()),
-            _ => return Err("This operand cannot be converted to a string")
+            Value::String(s) => Ok(s.clone()),
+            Value::Bool(b) => Ok((if *b {"true"} else {"false"}).to_string()),
+            Value::Number(num) => Ok(format_number(*num, precision)),
+            Value::Int(i) => Ok(i.to_string()),
+            Value::Symbol(sym) => Ok(sym.name().to_string()),
+            Value::StringBuilder(sb) => Ok(sb.borrow().clone()),
+            Value::Array(arr) => {
+                let items = arr.borrow().iter()
+                    .map(|item| item.to_string_with_precision(precision))
+                    .collect::<Result<Vec<String>, &'static str>>()?;
+                Ok(format!("[{}]", items.join(", ")))
+            },
+            Value::Map(map) => {
+                let entries = map.borrow().entries()
+                    .map(|(key, val)| Ok(format!("{}: {}",
+                        key.to_value().to_string_with_precision(precision)?,
+                        val.to_string_with_precision(precision)?
+                    )))
+                    .collect::<Result<Vec<String>, &'static str>>()?;
+                Ok(format!("{{{}}}", entries.join(", ")))
+            },
+            Value::Null => Ok("null".to_string()),
+            _ => Err("This operand cannot be converted to a string")
         }
     }
-    pub fn is_greater_than(&self, than: &Value) -> bool {
-        return match (self, than) {
-            (Value::Number(n1), Value::Number(n2)) => n1 > n2,
-            _ => false
+    /// The name of the type of the value, as user code sees it. Ints and
+    /// floats are both `"number"`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Number(_) | Value::Int(_) => "number",
+            Value::String(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::StringBuilder(_) => "stringbuilder",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Null => "null"
         }
     }
+    /// The value of an int or a float as a float, None for anything else.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Int(i) => Some(*i as f64),
+            _ => None
+        }
+    }
+    /// Applies an arithmetic operator. Two ints give an int unless int_op
+    /// returns None (overflow, inexact division...), then the float result is
+    /// used, same as when an int is mixed with a float. None for non-numbers.
+    pub fn arith(&self, other: &Value, int_op: fn(i64, i64) -> Option<i64>, float_op: fn(f64, f64) -> f64) -> Option<Value> {
+        if let (Value::Int(a), Value::Int(b)) = (self, other) {
+            return Some(match int_op(*a, *b) {
+                Some(res) => Value::Int(res),
+                None => Value::Number(float_op(*a as f64, *b as f64))
+            });
+        }
+        match (self.as_f64(), other.as_f64()) {
+            (Some(a), Some(b)) => Some(Value::Number(float_op(a, b))),
+            _ => None
+        }
+    }
+    /// Numbers are ordered numerically and strings lexicographically. Arrays
+    /// are ordered by their first differing element, and an array that is a
+    /// prefix of another is the smaller one. Comparing any other pair of
+    /// values is an error, and so is ordering NaN, since it's neither smaller
+    /// nor greater than anything.
+    ///
+    /// ```ignore
+    /// assert_eq!(Value::Number(2.).is_greater_than(&Value::Number(1.)), Ok(true));
+    /// assert!(Value::String("b".to_string()).is_greater_than(&Value::Number(1.)).is_err());
+    /// ```
+    pub fn is_greater_than(&self, than: &Value) -> Result<bool, &'static str> {
+        match (self, than) {
+            (Value::Int(i1), Value::Int(i2)) => Ok(i1 > i2),
+            (Value::String(s1), Value::String(s2)) => Ok(s1 > s2),
+            (Value::Array(a1), Value::Array(a2)) => {
+                let (a1, a2) = (a1.borrow(), a2.borrow());
+                for (x, y) in a1.iter().zip(a2.iter()) {
+                    if !x.is_equal_to(y) {
+                        return x.is_greater_than(y);
+                    }
+                }
+                Ok(a1.len() > a2.len())
+            },
+            _ => match (self.as_f64(), than.as_f64()) {
+                (Some(n1), Some(n2)) if n1.is_nan() || n2.is_nan() => Err("NaN cannot be ordered"),
+                (Some(n1), Some(n2)) => Ok(n1 > n2),
+                _ => Err("Only two numbers, strings or arrays can be compared")
+            }
+        }
+    }
+    /// Values are equal when they have the same type and the same contents.
+    /// Ints and floats compare by value, so `1 == 1.0`, and unlike IEEE
+    /// floats NaN equals itself. Arrays are equal when their elements are,
+    /// and maps when they have the same keys with equal values, in any order.
+    /// There is no other coercion, so `1 == "1"` and `0 == false` are both
+    /// false.
+    ///
+    /// ```ignore
+    /// assert!(Value::Null.is_equal_to(&Value::Null));
+    /// assert!(!Value::Number(0.).is_equal_to(&Value::Bool(false)));
+    /// ```
     pub fn is_equal_to(&self, to: &Value) -> bool {
-        return self == to;
+        match (self, to) {
+            (Value::Int(i), Value::Number(n)) | (Value::Number(n), Value::Int(i)) => *i as f64 == *n,
+            (Value::Number(n1), Value::Number(n2)) => n1 == n2 || (n1.is_nan() && n2.is_nan()),
+            (Value::Array(a1), Value::Array(a2)) => {
+                let (a1, a2) = (a1.borrow(), a2.borrow());
+                a1.len() == a2.len() && a1.iter().zip(a2.iter()).all(|(x, y)| x.is_equal_to(y))
+            },
+            (Value::Map(m1), Value::Map(m2)) => {
+                let (m1, m2) = (m1.borrow(), m2.borrow());
+                m1.len() == m2.len() && m1.entries().all(|(key, val)| m2.get(key).is_some_and(|other| val.is_equal_to(other)))
+            },
+            _ => self == to
+        }
+    }
+    /// Whether the value is the array or map `target`, or holds it somewhere
+    /// inside. Storing such a value into `target` would make a cycle, which
+    /// reference counting never frees and which printing and comparing would
+    /// follow forever, so IndexSet, MapSet and SliceSet refuse to.
+    pub fn contains_collection(&self, target: &Value) -> bool {
+        match (self, target) {
+            (Value::Array(a1), Value::Array(a2)) if Rc::ptr_eq(a1, a2) => true,
+            (Value::Map(m1), Value::Map(m2)) if Rc::ptr_eq(m1, m2) => true,
+            (Value::Array(arr), _) => arr.borrow().iter().any(|item| item.contains_collection(target)),
+            (Value::Map(map), _) => map.borrow().values().any(|val| val.contains_collection(target)),
+            _ => false
+        }
+    }
+}
+
+// all number to string conversions (trace, concatenation...) go through here.
+// Whole numbers print without a trailing .0, fractions as short as they
+// round-trip, -0 as 0, and non-finite numbers as Infinity, -Infinity and NaN
+fn format_number(num: f64, precision: Option<usize>) -> String {
+    if num == 0. {
+        return "0".to_string();
+    }
+    if num.is_nan() {
+        return "NaN".to_string();
+    }
+    if num.is_infinite() {
+        return (if num > 0. {"Infinity"} else {"-Infinity"}).to_string();
+    }
+    match precision {
+        Some(digits) => format_significant(num, digits),
+        None => num.to_string()
     }
 }
 
+fn format_significant(num: f64, digits: usize) -> String {
+    if digits == 0 {
+        return num.to_string();
+    }
+    let magnitude = num.abs().log10().floor() as i32;
+    let decimals = digits as i32 - 1 - magnitude;
+    let factor = 10f64.powi(decimals);
+    //subnormals need more than f64::MAX as the factor, print them in exponent form
+    if !factor.is_finite() {
+        return format!("{:.*e}", digits - 1, num);
+    }
+    let rounded = (num * factor).round() / factor;
+
+    let s = format!("{:.*}", decimals.max(0) as usize, rounded);
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+    else {s}
+}
+
 impl From<&Token> for Value {
     fn from(tok: &Token) -> Value {
-        return match tok.t {
-            TokenType::Number => Value::Number(
-                tok.val.parse::<f64>()
-                    .expect("Error parsing float! This might be a problem with the interpreter itself.")
-            ),
+        match tok.t {
+            TokenType::Number => match tok.val.parse::<i64>() {
+                Ok(i) => Value::Int(i),
+                _ => Value::Number(
+                    tok.val.parse::<f64>()
+                        .expect("Error parsing float! This might be a problem with the interpreter itself.")
+                )
+            },
             TokenType::String => {
                 let s = tok.val.clone();
                 Value::String(s[1..s.len()-1].to_string())
@@ -70,4 +373,186 @@ impl From<&Token> for Value {
             _ => unimplemented!()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truthy() {
+        assert!(Value::Bool(true).is_truthy());
+        assert!(Value::Number(-1.).is_truthy());
+        assert!(Value::String("queso".to_string()).is_truthy());
+
+        assert!(!Value::Bool(false).is_truthy());
+        assert!(!Value::Number(0.).is_truthy());
+        assert!(!Value::String("".to_string()).is_truthy());
+        assert!(!Value::Null.is_truthy());
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(Value::Number(2.).is_greater_than(&Value::Number(1.)), Ok(true));
+        assert_eq!(Value::Number(1.).is_greater_than(&Value::Number(1.)), Ok(false));
+        assert!(Value::String("b".to_string()).is_greater_than(&Value::Number(1.)).is_err());
+        assert!(Value::Null.is_greater_than(&Value::Null).is_err());
+
+        let (apple, banana) = (Value::String("apple".to_string()), Value::String("banana".to_string()));
+        assert_eq!(banana.is_greater_than(&apple), Ok(true));
+        assert_eq!(apple.is_greater_than(&banana), Ok(false));
+        assert_eq!(Value::String("b".to_string()).is_greater_than(&Value::String("a".to_string())), Ok(true));
+
+        assert!(Value::Null.is_equal_to(&Value::Null));
+        assert!(Value::Number(1.).is_equal_to(&Value::Number(1.)));
+        assert!(!Value::Number(1.).is_equal_to(&Value::String("1".to_string())));
+        assert!(!Value::Number(0.).is_equal_to(&Value::Bool(false)));
+
+        let nan = Value::Number(f64::NAN);
+        assert!(nan.is_equal_to(&nan));
+        assert!(!nan.is_equal_to(&Value::Number(1.)));
+        assert_eq!(nan.is_greater_than(&Value::Number(1.)), Err("NaN cannot be ordered"));
+        assert_eq!(Value::Int(1).is_greater_than(&nan), Err("NaN cannot be ordered"));
+    }
+
+    #[test]
+    fn test_array_comparison() {
+        let arr = |items: Vec<Value>| Value::Array(Rc::new(RefCell::new(items)));
+        let (one, two) = (Value::Int(1), Value::Int(2));
+
+        let prefix = arr(vec![one.clone()]);
+        let short = arr(vec![one.clone(), two.clone()]);
+        let long = arr(vec![one.clone(), two.clone(), one.clone()]);
+        assert_eq!(short.is_greater_than(&prefix), Ok(true));
+        assert_eq!(prefix.is_greater_than(&short), Ok(false));
+        assert_eq!(long.is_greater_than(&short), Ok(true));
+
+        let same = arr(vec![Value::Number(1.), Value::Number(2.)]);
+        assert!(short.is_equal_to(&same));
+        assert_eq!(short.is_greater_than(&same), Ok(false));
+        assert!(!short.is_equal_to(&long));
+
+        //[1, 3] > [1, 2] even though it's shorter than [1, 2, 1]
+        let differing = arr(vec![one.clone(), Value::Int(3)]);
+        assert_eq!(differing.is_greater_than(&long), Ok(true));
+        assert_eq!(arr(vec![short.clone()]).is_greater_than(&arr(vec![prefix.clone()])), Ok(true));
+
+        let mismatched = arr(vec![one, Value::String("2".to_string())]);
+        assert!(mismatched.is_greater_than(&short).is_err());
+        assert!(!mismatched.is_equal_to(&short));
+    }
+
+    #[test]
+    fn test_map_equality() {
+        let map = |entries: Vec<(i64, Value)>| {
+            let mut map = Map::new();
+            for (key, val) in entries {
+                map.insert(MapKey::Int(key), val);
+            }
+            Value::Map(Rc::new(RefCell::new(map)))
+        };
+
+        assert!(map(vec![(1, Value::Int(1))]).is_equal_to(&map(vec![(1, Value::Number(1.))])));
+        let nan = map(vec![(1, Value::Number(f64::NAN))]);
+        assert!(nan.is_equal_to(&nan));
+        assert!(nan.is_equal_to(&map(vec![(1, Value::Number(f64::NAN))])));
+
+        let (one, two) = ((1, Value::Int(1)), (2, Value::Int(2)));
+        assert!(map(vec![one.clone(), two.clone()]).is_equal_to(&map(vec![two.clone(), one.clone()])));
+        assert!(!map(vec![one.clone(), two]).is_equal_to(&map(vec![one.clone()])));
+        assert!(!map(vec![one]).is_equal_to(&map(vec![(1, Value::Int(2))])));
+    }
+
+    #[test]
+    fn test_ints() {
+        let add = |a: &Value, b: &Value| a.arith(b, i64::checked_add, |x, y| x + y);
+
+        assert_eq!(add(&Value::Int(2), &Value::Int(3)), Some(Value::Int(5)));
+        assert_eq!(add(&Value::Int(2), &Value::Number(0.5)), Some(Value::Number(2.5)));
+        assert_eq!(add(&Value::Int(i64::MAX), &Value::Int(1)), Some(Value::Number(i64::MAX as f64 + 1.)));
+        assert_eq!(add(&Value::Int(1), &Value::Null), None);
+
+        assert!(Value::Int(1).is_equal_to(&Value::Number(1.)));
+        assert!(!Value::Int(1).is_equal_to(&Value::Number(1.5)));
+        assert_eq!(Value::Int(2).is_greater_than(&Value::Number(1.5)), Ok(true));
+        assert_eq!(Value::Int(3).to_string(), Ok("3".to_string()));
+        assert!(!Value::Int(0).is_truthy());
+    }
+
+    #[test]
+    fn test_type_names() {
+        assert_eq!(Value::Bool(true).type_name(), "bool");
+        assert_eq!(Value::Number(1.5).type_name(), "number");
+        assert_eq!(Value::Int(1).type_name(), "number");
+        assert_eq!(Value::String("queso".to_string()).type_name(), "string");
+        assert_eq!(Value::Symbol(Symbol::new("x")).type_name(), "symbol");
+        assert_eq!(Value::Array(Rc::new(RefCell::new(vec![]))).type_name(), "array");
+        assert_eq!(Value::Map(Rc::new(RefCell::new(Map::new()))).type_name(), "map");
+        assert_eq!(Value::Null.type_name(), "null");
+    }
+
+    #[test]
+    fn test_map_keys() {
+        let key = |val: Value| MapKey::from_value(&val);
+
+        assert_eq!(key(Value::Number(1.)), Ok(MapKey::Int(1)));
+        assert_eq!(key(Value::Number(-0.)), key(Value::Int(0)));
+        assert_eq!(key(Value::Number(f64::NAN)), key(Value::Number(-f64::NAN)));
+        assert_eq!(key(Value::Number(1.5)).map(|k| k.to_value()), Ok(Value::Number(1.5)));
+        assert!(key(Value::Symbol(Symbol::new("x"))).is_err());
+    }
+
+    #[test]
+    fn test_map_order() {
+        let mut map = Map::new();
+        let names = ["queso", "salsa", "guac", "nachos"];
+        for (i, name) in names.iter().enumerate() {
+            map.insert(MapKey::String(name.to_string()), Value::Int(i as i64));
+        }
+        map.insert(MapKey::String("salsa".to_string()), Value::Null);
+
+        let keys: Vec<MapKey> = map.keys().cloned().collect();
+        assert_eq!(keys, names.iter().map(|name| MapKey::String(name.to_string())).collect::<Vec<MapKey>>());
+        assert_eq!(map.values().cloned().collect::<Vec<Value>>(), vec![Value::Int(0), Value::Null, Value::Int(2), Value::Int(3)]);
+        assert_eq!(
+            Value::Map(Rc::new(RefCell::new(map.clone()))).to_string(),
+            Ok("{queso: 0, salsa: null, guac: 2, nachos: 3}".to_string())
+        );
+
+        let mut reversed = Map::new();
+        for (key, val) in map.entries().collect::<Vec<(&MapKey, &Value)>>().into_iter().rev() {
+            reversed.insert(key.clone(), val.clone());
+        }
+        assert!(map == reversed);
+    }
+
+    #[test]
+    fn test_number_format() {
+        let fmt = |n: f64| Value::Number(n).to_string().unwrap();
+
+        assert_eq!(fmt(5.0), "5");
+        assert_eq!(fmt(2.5), "2.5");
+        assert_eq!(fmt(-0.0), "0");
+        assert_eq!(fmt(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(fmt(1e21), "1000000000000000000000");
+        assert_eq!(fmt(f64::INFINITY), "Infinity");
+        assert_eq!(fmt(f64::NEG_INFINITY), "-Infinity");
+        assert_eq!(fmt(f64::NAN), "NaN");
+        assert_eq!(fmt(-f64::NAN), "NaN");
+        assert_eq!(Value::Number(f64::INFINITY).to_string_with_precision(Some(3)), Ok("Infinity".to_string()));
+    }
+
+    #[test]
+    fn test_precision() {
+        let x = Value::Number(1.23456);
+        assert_eq!(x.to_string(), Ok("1.23456".to_string()));
+        assert_eq!(x.to_string_with_precision(Some(3)), Ok("1.23".to_string()));
+        assert_eq!(Value::Number(1234.5).to_string_with_precision(Some(3)), Ok("1230".to_string()));
+        assert_eq!(Value::Number(2.5).to_string_with_precision(Some(3)), Ok("2.5".to_string()));
+        assert_eq!(Value::Number(-0.0012345).to_string_with_precision(Some(2)), Ok("-0.0012".to_string()));
+        assert_eq!(x.to_string_with_precision(Some(0)), Ok("1.23456".to_string()));
+
+        assert_eq!(Value::Number(5e-324).to_string_with_precision(Some(3)), Ok("4.94e-324".to_string()));
+        assert_eq!(Value::Number(-1e-310).to_string_with_precision(Some(3)), Ok("-1.00e-310".to_string()));
+    }
+}
@@ -1,258 +1,1221 @@
 use crate::*;
+use std::time::{Instant, Duration};
+use std::io::{self, Write};
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 
 type Stack = Vec<Value>;
 
+// how many instructions run between checks of the more expensive limits
+const CHECK_INTERVAL: usize = 1024;
+
+// the tolerance ApproxEq uses when it's given null instead of one
+const DEFAULT_EPSILON: f64 = 1e-9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Json
+}
+
+// an in-memory writer that can still be read after being handed to the VM
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuf {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+// every index into a string goes through here, negative indices count from
+// the end so -1 is the last element
+fn normalize_index(len: usize, i: i64) -> Result<usize, &'static str> {
+    let id = if i < 0 {i + len as i64} else {i};
+    if id < 0 || id >= len as i64 {
+        return Err("Index out of bounds");
+    }
+    Ok(id as usize)
+}
+
+// like normalize_index, but the bound of an exclusive range may also be one
+// past the last element
+fn normalize_bound(len: usize, i: i64) -> Result<usize, &'static str> {
+    if i == len as i64 {Ok(len)} else {normalize_index(len, i)}
+}
+
+// the text of FormatStr, template with every {i} replaced by args[i]
+fn format_placeholders(template: &str, args: &[String]) -> Result<String, &'static str> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            },
+            '{' => {
+                let mut digits = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(d) if d.is_ascii_digit() => digits.push(d),
+                        _ => return Err("A placeholder has to be an index in braces")
+                    }
+                }
+                let id: usize = digits.parse().map_err(|_| "A placeholder has to be an index in braces")?;
+                out.push_str(args.get(id).ok_or("Placeholder index out of range")?);
+            },
+            '}' => return Err("Unmatched } in a format string, use }} for a literal one"),
+            c => out.push(c)
+        }
+    }
+    Ok(out)
+}
+
+// the value of an integer-valued number, None for fractions and non-numbers
+fn as_int(val: &Value) -> Option<i64> {
+    match val {
+        Value::Int(i) => Some(*i),
+        Value::Number(n) if n.fract() == 0. && n.abs() <= i64::MAX as f64 => Some(*n as i64),
+        _ => None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    Continue,
+    // the program finished with this value on top of the stack
+    Halted(Value),
+    // paused before the first instruction of a line with a breakpoint
+    BreakpointHit(u32)
+}
+
+struct TryHandler {
+    catch: usize,
+    // the stack is truncated to this height before the error is pushed
+    height: usize
+}
+
 pub struct VM {
     chk: Chunk,
     cur_instr: usize,
     stack: Stack,
+    handlers: Vec<TryHandler>,
+    // errors are &'static str, so an instruction that builds its message at
+    // runtime leaves the full text here for catch and finish to use instead
+    error_detail: Option<String>,
+    // kept across execute calls and resets
+    globals: HashMap<u16, Value>,
+
+    out: Box<dyn Write>,
+
+    debug: bool,
+    trace_format: TraceFormat,
+    float_precision: Option<usize>,
 
-    debug: bool
+    strict: bool,
+    warnings: Vec<String>,
+
+    instr_count: u64,
+    instr_limit: Option<u64>,
+    timeout: Option<Duration>,
+    started: Instant,
+    collection_limit: Option<usize>,
+
+    // dispatch counts per instruction mnemonic, None when profiling is off
+    profile: Option<HashMap<&'static str, u64>>,
+
+    // every symbol made by this VM by name, kept across resets
+    symbols: HashMap<String, Symbol>,
+
+    breakpoints: HashSet<u32>,
+    // the line of the last instruction run with breakpoints on, and the
+    // instruction execution paused on so resuming doesn't hit it again
+    cur_line: Option<u32>,
+    break_instr: Option<usize>
 }
 
 impl VM {
     pub fn new(debug: bool) -> VM {
+        VM::with_output(debug, Box::new(io::stdout()))
+    }
+
+    // trace output is written to out instead of stdout
+    pub fn with_output(debug: bool, out: Box<dyn Write>) -> VM {
         VM {
             chk: Chunk::new(),
             cur_instr: 0,
             stack: Stack::new(),
-            debug
+            handlers: Vec::new(),
+            error_detail: None,
+            globals: HashMap::new(),
+            out,
+            debug,
+            trace_format: TraceFormat::Text,
+            float_precision: None,
+
+            strict: false,
+            warnings: Vec::<String>::new(),
+
+            instr_count: 0,
+            instr_limit: None,
+            timeout: None,
+            started: Instant::now(),
+            collection_limit: None,
+
+            profile: None,
+
+            symbols: HashMap::new(),
+
+            breakpoints: HashSet::new(),
+            cur_line: None,
+            break_instr: None
+        }
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder {
+            debug: false,
+            out: None,
+            trace_format: TraceFormat::Text,
+            float_precision: None,
+            strict: false,
+            instr_limit: None,
+            timeout: None,
+            collection_limit: None,
+            profiling: false
+        }
+    }
+
+    pub fn set_trace_format(&mut self, format: TraceFormat) {
+        self.trace_format = format;
+    }
+
+    // number of significant digits used when numbers are traced or
+    // concatenated with strings, full precision by default or when digits is 0
+    pub fn set_float_precision(&mut self, digits: usize) {
+        self.float_precision = Some(digits);
+    }
+
+    // lint mode, reports likely mistakes through warnings()
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn warn_float_equality(&mut self, a: &Value, b: &Value) {
+        if let (Value::Number(n1), Value::Number(n2)) = (a, b) {
+            if n1.fract() != 0. && n2.fract() != 0. {
+                let warning = self.runtime_error("exact float comparison may be unreliable");
+                self.warnings.push(warning.to_string());
+            }
+        }
+    }
+
+    // max number of instructions a single execute call may run
+    pub fn set_instruction_limit(&mut self, limit: u64) {
+        self.instr_limit = Some(limit);
+    }
+
+    // max number of elements an array or map may hold, unbounded by default
+    pub fn set_collection_limit(&mut self, limit: usize) {
+        self.collection_limit = Some(limit);
+    }
+
+    fn check_collection_len(&self, len: usize) -> Result<(), &'static str> {
+        match self.collection_limit {
+            Some(limit) if len > limit => Err("Collection size limit exceeded"),
+            _ => Ok(())
         }
     }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instr_count
+    }
+
+    // wall-clock budget counted from the start of each execute, resume or
+    // step call, so time spent paused between steps doesn't count
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
         
-    fn next_instr(&mut self) -> Option<&Instruction> {
-        self.cur_instr += 1;
-        self.chk.try_get_instr(self.cur_instr - 1)
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = if enabled {Some(HashMap::new())} else {None};
+    }
+
+    // how many times each instruction was dispatched by the last execute call
+    pub fn profile(&self) -> Option<&HashMap<&'static str, u64>> {
+        self.profile.as_ref()
+    }
+
+    // the symbol with this name, the same one every time it's asked for
+    pub fn intern(&mut self, name: &str) -> Value {
+        let sym = self.symbols.entry(name.to_string())
+            .or_insert_with(|| Symbol::new(name));
+        Value::Symbol(sym.clone())
+    }
+
+    // step and resume pause on source lines with a breakpoint, execute ignores them
+    pub fn add_breakpoint(&mut self, line: u32) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: u32) {
+        self.breakpoints.remove(&line);
+    }
+
+    // where a forward jump by offset from the current instruction lands,
+    // erroring if that's past the last instruction
+    fn jump_target(&self, offset: usize) -> Result<usize, &'static str> {
+        let target = self.cur_instr + offset;
+        if target >= self.chk.instrs.len() {
+            return Err("Jump out of bounds");
+        }
+        Ok(target)
+    }
+
+    // malformed bytecode errors instead of panicking, so a host embedding the
+    // VM can recover from a compiler bug
+    fn pop_stack(&mut self) -> Result<Value, &'static str> {
+        self.stack.pop().ok_or("Stack underflow")
+    }
+
+    // pops the two operands of a binary operator, returned in push order
+    fn pop_two(&mut self) -> Result<(Value, Value), &'static str> {
+        if self.stack.len() < 2 {
+            return Err("Stack underflow");
+        }
+        let b = self.stack.pop().expect("the length was checked");
+        let a = self.stack.pop().expect("the length was checked");
+        Ok((a, b))
+    }
+
+    // the operands of a bitwise operator, floats count if they're whole
+    fn pop_two_ints(&mut self) -> Result<(i64, i64), &'static str> {
+        let as_int = |val: Value| as_int(&val).ok_or("Bitwise operators can only be used with integers");
+        let (a, b) = self.pop_two()?;
+        Ok((as_int(a)?, as_int(b)?))
     }
 
-    fn pop_stack(&mut self) -> Value {
-        self.stack.pop()
-            .expect("Failed to pop a value of the stack. This might be a problem with the interpreter itself.")
+    fn pop_string(&mut self) -> Result<String, &'static str> {
+        match self.pop_stack()? {
+            Value::String(s) => Ok(s),
+            _ => Err("Expected a string")
+        }
+    }
+
+    // an index before normalize_index, so it may still be negative
+    fn pop_index(&mut self) -> Result<i64, &'static str> {
+        match self.pop_stack()? {
+            Value::Int(i) => Ok(i),
+            Value::Number(n) if n.fract() == 0. => Ok(n as i64),
+            Value::Number(_) => Err("An index has to be an integer"),
+            _ => Err("An index has to be a number")
+        }
     }
 
-    fn get_stack(&self, id: u16) -> &Value {
-        self.stack.get(id as usize).expect("Couldn't access a value on the stack. This is a problem with the interpreter itself")
+    // a slice bound, None if it was left out (null)
+    fn pop_bound(&mut self) -> Result<Option<i64>, &'static str> {
+        if let Value::Null = self.get_stack_top()? {
+            self.pop_stack()?;
+            return Ok(None);
+        }
+        self.pop_index().map(Some)
+    }
+
+    fn get_stack(&self, id: u16) -> Result<&Value, &'static str> {
+        self.stack.get(id as usize).ok_or("Stack slot out of range")
     }
 
-    fn get_stack_top(&self) -> &Value {
-        self.get_stack(self.stack.len() as u16 - 1)
+    fn get_stack_top(&self) -> Result<&Value, &'static str> {
+        self.stack.last().ok_or("Stack underflow")
     }
 
-    fn set_stack(&mut self, id: u16, val: Value) {
-        self.stack[id as usize] = val;
+    fn set_stack(&mut self, id: u16, val: Value) -> Result<(), &'static str> {
+        let slot = self.stack.get_mut(id as usize).ok_or("Stack slot out of range")?;
+        *slot = val;
+        Ok(())
     }
 
     fn print_stack(&self) {
-        print!("stack ");
-        if self.stack.len() == 0 {
-            print!("<empty>");
+        println!("{}", self.stack_dump());
+    }
+
+    // shared values are only summarized, their contents could be long
+    fn stack_dump(&self) -> String {
+        let mut dump = "stack ".to_string();
+        if self.stack.is_empty() {
+            dump.push_str("<empty>");
         }
         for val in self.stack.iter() {
-            print!("| {:?} ", val);
+            let desc = match val {
+                Value::String(s) => format!("{:?}", s),
+                Value::StringBuilder(sb) => format!("stringbuilder[{}]", sb.borrow().chars().count()),
+                Value::Array(arr) => format!("array[{}]", arr.borrow().len()),
+                Value::Map(map) => format!("map[{}]", map.borrow().len()),
+                val => format!("{:?}", val)
+            };
+            dump.push_str(&format!("| {} ", desc));
+        }
+        dump
+    }
+
+    // one line per instruction, e.g. {"ip":3,"op":"Add","stack_depth":2,"line":1}
+    fn json_trace_line(&self) -> String {
+        let op = self.chk.get_instr(self.cur_instr).mnemonic();
+        format!("{{\"ip\":{},\"op\":\"{}\",\"stack_depth\":{},\"line\":{}}}",
+            self.cur_instr,
+            op,
+            self.stack.len(),
+            self.chk.get_line_no(self.cur_instr as u32)
+        )
+    }
+
+    // [line] value or [line] label: value, for the instruction just fetched
+    fn format_trace(&self, label: Option<&str>, val: &Value) -> String {
+        let val = val.to_string_with_precision(self.float_precision).unwrap_or_default();
+        //add filename
+        let line_no = self.chk.get_line_no(self.cur_instr as u32 - 1);
+        match label {
+            Some(label) => format!("[{}] {}: {}", line_no, label, val),
+            None => format!("[{}] {}", line_no, val)
+        }
+    }
+
+    // returns the value left on top of the stack when the program halts,
+    // null if the stack is empty
+    pub fn execute(&mut self, chk: Chunk) -> Result<Value, RuntimeError> {
+        self.load(chk);
+        match self.run(false)? {
+            StepResult::Halted(val) => Ok(val),
+            _ => unreachable!("Breakpoints are ignored by execute. This is a problem with the interpreter itself")
         }
-        println!();
     }
 
-    pub fn execute(&mut self, chk: Chunk) -> Result<(), &'static str> {
+    // installs chk with an empty stack so the VM can be reused for another
+    // program, settings like the output, limits and profiling are kept, and
+    // so are globals. Run it with resume
+    pub fn reset(&mut self, chk: Chunk) {
+        self.stack.clear();
+        self.load(chk);
+    }
+
+    // runs chk as the next line of a REPL session. Like execute, but any
+    // values a previous line left on the stack are dropped first. Globals and
+    // interned symbols carry over from line to line
+    pub fn eval_chunk(&mut self, chk: Chunk) -> Result<Value, RuntimeError> {
+        self.stack.clear();
+        self.execute(chk)
+    }
+
+    fn load(&mut self, chk: Chunk) {
         self.chk = chk;
         self.cur_instr = 0;
-        self.run()
+        self.instr_count = 0;
+        self.handlers.clear();
+        self.error_detail = None;
+        self.cur_line = None;
+        self.break_instr = None;
+        self.started = Instant::now();
+        self.warnings.clear();
+        if let Some(profile) = &mut self.profile {
+            profile.clear();
+        }
     }
 
-    fn run(&mut self) -> Result<(), &'static str> {
-        if self.debug {
+    // runs from the current instruction until the program halts or hits a breakpoint
+    pub fn resume(&mut self) -> Result<StepResult, RuntimeError> {
+        self.started = Instant::now();
+        self.run(true)
+    }
+
+    // runs chk with the output captured instead of written to out, returns
+    // the result of execute and the output text
+    pub fn run_capturing(&mut self, chk: Chunk) -> Result<(Value, String), RuntimeError> {
+        let buf = SharedBuf::default();
+        let out = std::mem::replace(&mut self.out, Box::new(buf.clone()));
+        let res = self.execute(chk);
+        self.out = out;
+
+        Ok((res?, buf.text()))
+    }
+
+    // executes a single instruction, for driving the VM from a debugger
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        self.started = Instant::now();
+        let res = self.exec_instr(true);
+        self.finish(res)
+    }
+
+    fn run(&mut self, breakpoints: bool) -> Result<StepResult, RuntimeError> {
+        let res = self.dispatch(breakpoints);
+        self.finish(res)
+    }
+
+    // flushes the output and attaches the line to errors
+    fn finish<T>(&mut self, res: Result<T, &'static str>) -> Result<T, RuntimeError> {
+        let flushed = self.out.flush();
+        res.and_then(|val| flushed.map(|_| val).map_err(|_| "Failed to write the output"))
+            .map_err(|msg| {
+                let msg = self.error_detail.take().unwrap_or_else(|| msg.to_string());
+                self.runtime_error(&msg)
+            })
+    }
+
+    // attaches the line of the last fetched instruction to the error
+    fn runtime_error(&self, msg: &str) -> RuntimeError {
+        let line = if self.chk.instrs.is_empty() {0} else {
+            let instr_id = self.cur_instr.saturating_sub(1).min(self.chk.instrs.len() - 1);
+            self.chk.get_line_no(instr_id as u32)
+        };
+        RuntimeError::new(msg, line)
+    }
+
+    fn dispatch(&mut self, breakpoints: bool) -> Result<StepResult, &'static str> {
+        if self.debug && self.trace_format == TraceFormat::Text {
             println!("\nINSTRUCTIONS:");
         }
 
         loop {
+            match self.exec_instr(breakpoints)? {
+                StepResult::Continue => {},
+                res => return Ok(res)
+            }
+        }
+    }
 
-            if self.debug {
-                self.print_stack();
-                self.chk.print_instr(self.cur_instr, false);
+    fn halted(&self) -> StepResult {
+        StepResult::Halted(self.stack.last().cloned().unwrap_or(Value::Null))
+    }
+
+    fn exec_instr(&mut self, breakpoints: bool) -> Result<StepResult, &'static str> {
+        if self.cur_instr >= self.chk.instrs.len() {
+            return Ok(self.halted());
+        }
 
-                println!();
+        if breakpoints && !self.breakpoints.is_empty() {
+            let cur = self.cur_instr;
+            let line = self.chk.get_line_no(cur as u32);
+            // a line is entered from another one, or by jumping back to its
+            // first instruction like a loop written on a single line does
+            let starts_line = cur == 0 || self.chk.get_line_no(cur as u32 - 1) != line;
+            let entered = self.cur_line != Some(line) || starts_line;
+            if entered && self.breakpoints.contains(&line) && self.break_instr != Some(cur) {
+                self.break_instr = Some(cur);
+                return Ok(StepResult::BreakpointHit(line));
             }
+            self.break_instr = None;
+            self.cur_line = Some(line);
+        }
 
-            if let Some(next) = self.next_instr() {
-                match next {
-                    Instruction::Return => {
-                        break;
-                    },
-                    Instruction::PushConstant(id) => {
-                        let id = *id;
-                        let constant: &Value = self.chk.get_const(id);
-                        self.stack.push(constant.clone());
-                    },
-                    Instruction::PushTrue => {
-                        self.stack.push(Value::Bool(true));
-                    },
-                    Instruction::PushFalse => {
-                        self.stack.push(Value::Bool(false));
-                    },
-                    Instruction::PushNull => {
-                        self.stack.push(Value::Null);
-                    },
-                    Instruction::Negate => {
-                        let val = self.pop_stack();
-                        match val.to_number() {
-                            Ok(num) => self.stack.push(Value::Number(-num)),
-                            Err(err) => return Err(err)
-                        }                       
-                    },
-                    Instruction::ToNumber => {
-                        let val = self.pop_stack();
-                        match val.to_number() {
-                            Ok(num) => self.stack.push(Value::Number(num)),
-                            Err(err) => return Err(err)
-                        }                      
-                    },
-                    Instruction::Not => {
-                        let val = self.pop_stack();
-                        self.stack.push(Value::Bool(!val.is_truthy()))                     
-                    },
-                    Instruction::Add => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        match (a, b) {
-                            (Value::Number(n1), Value::Number(n2)) => {
-                                self.stack.push(Value::Number(n1 + n2));
-                            },
-                            (Value::String(s1), v @ _) | (v @ _, Value::String(s1)) => {
-                                match v.to_string() {
-                                    Ok(s2) => self.stack.push(Value::String(s1 + &s2)),
-                                    Err(err) => return Err(err)
-                                }
-                            },
-                            _ => {
-                                return Err("The addition operator can only be used with numbers and strings");
-                            }
-                        }
-                    },
-                    Instruction::Subtract => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        match (a, b) {
-                            (Value::Number(n1), Value::Number(n2)) => {
-                                self.stack.push(Value::Number(n1 - n2));
-                            },
-                            _ => {
-                                return Err("The subtraction operator can only be used with numbers");
+        if let Some(limit) = self.instr_limit {
+            if self.instr_count >= limit {
+                return Err("Instruction budget exhausted");
+            }
+        }
+        self.instr_count += 1;
+        if self.instr_count.is_multiple_of(CHECK_INTERVAL as u64) {
+            if let Some(timeout) = self.timeout {
+                if self.started.elapsed() > timeout {
+                    return Err("Execution timed out");
+                }
+            }
+        }
+
+        if let Some(profile) = &mut self.profile {
+            let op = self.chk.get_instr(self.cur_instr).mnemonic();
+            *profile.entry(op).or_insert(0) += 1;
+        }
+
+        if self.debug {
+            match self.trace_format {
+                TraceFormat::Text => {
+                    self.print_stack();
+                    self.chk.print_instr(self.cur_instr, false);
+
+                    println!();
+                },
+                TraceFormat::Json => {
+                    let line = self.json_trace_line();
+                    writeln!(self.out, "{}", line).map_err(|_| "Failed to write the output")?;
+                }
+            }
+        }
+
+        let res = self.run_instr();
+        self.catch(res)
+    }
+
+    // errors raised by an instruction jump to the innermost try handler if
+    // there is one. Running out of instructions or time can't be caught
+    fn catch(&mut self, res: Result<StepResult, &'static str>) -> Result<StepResult, &'static str> {
+        match res {
+            Err(msg) => match self.handlers.pop() {
+                Some(handler) => {
+                    self.stack.truncate(handler.height);
+                    let msg = self.error_detail.take().unwrap_or_else(|| msg.to_string());
+                    self.stack.push(Value::String(msg));
+                    self.cur_instr = handler.catch;
+                    Ok(StepResult::Continue)
+                },
+                None => Err(msg)
+            },
+            res => res
+        }
+    }
+
+    fn run_instr(&mut self) -> Result<StepResult, &'static str> {
+        self.cur_instr += 1;
+        //borrows only chk, so arms can still read the stack while holding operands
+        if let Some(next) = self.chk.try_get_instr(self.cur_instr - 1) {
+            match next {
+                Instruction::Halt => {
+                    self.cur_instr = self.chk.instrs.len();
+                    return Ok(self.halted());
+                },
+                //functions don't exist yet, so there's never a frame to return to
+                Instruction::Return => {
+                    return Err("Cannot return outside of a function");
+                },
+                Instruction::PushConstant(id) => {
+                    let id = u32::from(*id);
+                    let constant = self.chk.try_get_const(id)?.clone();
+                    self.stack.push(constant);
+                },
+                Instruction::PushConstantLong(id) => {
+                    let id = *id;
+                    let constant = self.chk.try_get_const(id)?.clone();
+                    self.stack.push(constant);
+                },
+                Instruction::PushSymbol(id) => {
+                    let id = u32::from(*id);
+                    let name = self.chk.try_get_const(id)?.to_string()?;
+                    let sym = self.intern(&name);
+                    self.stack.push(sym);
+                },
+                Instruction::PushTrue => {
+                    self.stack.push(Value::Bool(true));
+                },
+                Instruction::PushFalse => {
+                    self.stack.push(Value::Bool(false));
+                },
+                Instruction::PushNull => {
+                    self.stack.push(Value::Null);
+                },
+                Instruction::Negate => {
+                    let val = self.pop_stack()?;
+                    match val {
+                        Value::Int(i) => self.stack.push(
+                            i.checked_neg().map(Value::Int).unwrap_or(Value::Number(-(i as f64)))
+                        ),
+                        _ => self.stack.push(Value::Number(-val.to_number()?))
+                    }
+                },
+                Instruction::ToNumber => {
+                    let val = self.pop_stack()?;
+                    match val {
+                        Value::Int(_) => self.stack.push(val),
+                        _ => self.stack.push(Value::Number(val.to_number()?))
+                    }
+                },
+                Instruction::Not => {
+                    let val = self.pop_stack()?;
+                    self.stack.push(Value::Bool(!val.is_truthy()))                     
+                },
+                Instruction::Add => {
+                    let (a, b) = self.pop_two()?;
+
+                    if let Some(res) = a.arith(&b, i64::checked_add, |x, y| x + y) {
+                        self.stack.push(res);
+                        return Ok(StepResult::Continue);
+                    }
+                    match (a, b) {
+                        (Value::String(s1), v) | (v, Value::String(s1)) => {
+                            match v.to_string_with_precision(self.float_precision) {
+                                Ok(s2) => self.stack.push(Value::String(s1 + &s2)),
+                                Err(err) => return Err(err)
                             }
+                        },
+                        _ => {
+                            return Err("The addition operator can only be used with numbers and strings");
                         }
-                    },
-                    Instruction::Multiply => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        match (a, b) {
-                            (Value::Number(n1), Value::Number(n2)) => {
-                                self.stack.push(Value::Number(n1 * n2));
-                            },
-                            _ => {
-                                return Err("The multiplication operator can only be used with numbers");
+                    }
+                },
+                Instruction::Subtract => {
+                    let (a, b) = self.pop_two()?;
+
+                    let res = a.arith(&b, i64::checked_sub, |x, y| x - y)
+                        .ok_or("The subtraction operator can only be used with numbers")?;
+                    self.stack.push(res);
+                },
+                Instruction::Multiply => {
+                    let (a, b) = self.pop_two()?;
+
+                    let res = a.arith(&b, i64::checked_mul, |x, y| x * y)
+                        .ok_or("The multiplication operator can only be used with numbers")?;
+                    self.stack.push(res);
+                },
+                Instruction::Divide => {
+                    let (a, b) = self.pop_two()?;
+
+                    if b.as_f64() == Some(0.) {
+                        return Err("Cannot divide by 0");
+                    }
+                    //ints only stay ints when they divide evenly
+                    let res = a.arith(&b, |x, y| if x.checked_rem(y)? == 0 {x.checked_div(y)} else {None}, |x, y| x / y)
+                        .ok_or("The division operator can only be used with numbers")?;
+                    self.stack.push(res);
+                },
+                Instruction::Modulo => {
+                    let (a, b) = self.pop_two()?;
+
+                    if b.as_f64() == Some(0.) {
+                        return Err("Cannot modulo by 0");
+                    }
+                    let res = a.arith(&b, i64::checked_rem, |x, y| x % y)
+                        .ok_or("The modulo operator can only be used with numbers")?;
+                    self.stack.push(res);
+                },
+                Instruction::Exponent => {
+                    let (a, b) = self.pop_two()?;
+
+                    //negative int exponents fall back to floats
+                    let res = a.arith(&b, |base, exp| base.checked_pow(u32::try_from(exp).ok()?), f64::powf)
+                        .ok_or("The exponentiation operator can only be used with numbers")?;
+                    self.stack.push(res);
+                },
+                Instruction::BitAnd => {
+                    let (a, b) = self.pop_two_ints()?;
+                    self.stack.push(Value::Int(a & b));
+                },
+                Instruction::BitOr => {
+                    let (a, b) = self.pop_two_ints()?;
+                    self.stack.push(Value::Int(a | b));
+                },
+                Instruction::BitXor => {
+                    let (a, b) = self.pop_two_ints()?;
+                    self.stack.push(Value::Int(a ^ b));
+                },
+                Instruction::Shl | Instruction::Shr => {
+                    let is_shl = matches!(next, Instruction::Shl);
+                    let (a, b) = self.pop_two_ints()?;
+
+                    let count = u32::try_from(b).ok().filter(|count| *count < 64)
+                        .ok_or("Shift count has to be between 0 and 63")?;
+                    self.stack.push(Value::Int(if is_shl {a << count} else {a >> count}));
+                },
+                Instruction::IsPowerOfTwo => {
+                    let n = as_int(&self.pop_stack()?).ok_or("Expected an integer")?;
+                    self.stack.push(Value::Bool(n > 0 && n & (n - 1) == 0));
+                },
+                Instruction::Log2 => {
+                    let n = as_int(&self.pop_stack()?).ok_or("Expected an integer")?;
+                    if n < 1 {
+                        return Err("log2 is only defined for positive numbers");
+                    }
+                    //exact for powers of two, like 2 ** n gives an int
+                    self.stack.push(if n & (n - 1) == 0 {
+                        Value::Int(i64::from(n.trailing_zeros()))
+                    } else {
+                        Value::Number((n as f64).log2())
+                    });
+                },
+                Instruction::Equal => {
+                    let (a, b) = self.pop_two()?;
+                    if self.strict {self.warn_float_equality(&a, &b)}
+
+                    self.stack.push(Value::Bool(a.is_equal_to(&b)));
+                },
+                Instruction::NotEqual => {
+                    let (a, b) = self.pop_two()?;
+                    if self.strict {self.warn_float_equality(&a, &b)}
+
+                    self.stack.push(Value::Bool(!a.is_equal_to(&b)));
+                },
+                Instruction::GreaterEqual => {
+                    let (a, b) = self.pop_two()?;
+
+                    let gt = a.is_greater_than(&b)?;
+                    self.stack.push(Value::Bool(gt || a.is_equal_to(&b)));
+                },
+                Instruction::LessEqual => {
+                    let (a, b) = self.pop_two()?;
+
+                    let lt = b.is_greater_than(&a)?;
+                    self.stack.push(Value::Bool(lt || a.is_equal_to(&b)));
+                },
+                Instruction::Greater => {
+                    let (a, b) = self.pop_two()?;
+
+                    self.stack.push(Value::Bool(a.is_greater_than(&b)?));
+                },
+                Instruction::Less => {
+                    let (a, b) = self.pop_two()?;
+
+                    self.stack.push(Value::Bool(b.is_greater_than(&a)?));
+                },
+                Instruction::InRange => {
+                    let (lo, hi) = self.pop_two()?;
+                    let x = self.pop_stack()?;
+
+                    let above_lo = x.is_greater_than(&lo)? || x.is_equal_to(&lo);
+                    let below_hi = hi.is_greater_than(&x)? || x.is_equal_to(&hi);
+                    self.stack.push(Value::Bool(above_lo && below_hi));
+                },
+                Instruction::ApproxEq => {
+                    let epsilon = match self.pop_stack()? {
+                        Value::Null => DEFAULT_EPSILON,
+                        epsilon => epsilon.as_f64().ok_or("The tolerance has to be a number")?
+                    };
+                    let (a, b) = self.pop_two()?;
+                    match (a.as_f64(), b.as_f64()) {
+                        //equal values are always close, even NaN or infinity
+                        (Some(x), Some(y)) => self.stack.push(Value::Bool(a.is_equal_to(&b) || (x - y).abs() <= epsilon)),
+                        _ => return Err("Only numbers can be compared approximately")
+                    }
+                },
+                Instruction::AssertEq => {
+                    let precision = self.float_precision;
+                    let message = self.pop_stack()?.to_string_with_precision(precision)?;
+                    let (actual, expected) = self.pop_two()?;
+                    if !actual.is_equal_to(&expected) {
+                        self.error_detail = Some(format!("expected {} but got {}: {}",
+                            expected.to_string_with_precision(precision)?,
+                            actual.to_string_with_precision(precision)?,
+                            message
+                        ));
+                        return Err("Assertion failed");
+                    }
+                    self.stack.push(Value::Null);
+                },
+                Instruction::Trace => {
+                    let a = self.pop_stack()?;
+
+                    let line = self.format_trace(None, &a);
+                    writeln!(self.out, "{}", line).map_err(|_| "Failed to write the output")?;
+                    //maybe don't pop at all?
+
+                    self.stack.push(a);
+                },
+                Instruction::TraceLabeled(label_id) => {
+                    let label_id = u32::from(*label_id);
+                    let a = self.pop_stack()?;
+
+                    let label = self.chk.try_get_const(label_id)?.to_string()?;
+                    let line = self.format_trace(Some(&label), &a);
+                    writeln!(self.out, "{}", line).map_err(|_| "Failed to write the output")?;
+
+                    self.stack.push(a);
+                },
+                Instruction::StrLen => {
+                    let s = self.pop_string()?;
+                    self.stack.push(Value::Int(s.chars().count() as i64));
+                },
+                Instruction::Substring => {
+                    let end = self.pop_index()?;
+                    let start = self.pop_index()?;
+                    let s = self.pop_string()?;
+
+                    let len = s.chars().count();
+                    let start = normalize_bound(len, start)?;
+                    let end = normalize_bound(len, end)?;
+                    if start > end {
+                        return Err("Substring range out of bounds");
+                    }
+                    let sub: String = s.chars().skip(start).take(end - start).collect();
+                    self.stack.push(Value::String(sub));
+                },
+                Instruction::CharAt => {
+                    let id = self.pop_index()?;
+                    let s = self.pop_string()?;
+
+                    let id = normalize_index(s.chars().count(), id)?;
+                    let c = s.chars().nth(id).expect("normalize_index checks the bounds");
+                    self.stack.push(Value::String(c.to_string()));
+                },
+                Instruction::FormatStr(n) => {
+                    let n = *n as usize;
+                    if self.stack.len() < n + 1 {
+                        return Err("Not enough values on the stack");
+                    }
+                    let args = self.stack.split_off(self.stack.len() - n).iter()
+                        .map(|arg| arg.to_string_with_precision(self.float_precision))
+                        .collect::<Result<Vec<String>, &'static str>>()?;
+                    let template = self.pop_string()?;
+                    self.stack.push(Value::String(format_placeholders(&template, &args)?));
+                },
+                Instruction::SbNew => {
+                    self.stack.push(Value::StringBuilder(Rc::new(RefCell::new(String::new()))));
+                },
+                Instruction::SbAppend => {
+                    let fragment = self.pop_stack()?.to_string_with_precision(self.float_precision)?;
+                    match self.get_stack_top()? {
+                        Value::StringBuilder(sb) => sb.borrow_mut().push_str(&fragment),
+                        _ => return Err("Expected a string builder")
+                    }
+                },
+                Instruction::SbFinish => {
+                    match self.pop_stack()? {
+                        Value::StringBuilder(sb) => {
+                            //no copy needed when nothing else refers to the builder
+                            let s = Rc::try_unwrap(sb).map(RefCell::into_inner)
+                                .unwrap_or_else(|sb| sb.borrow().clone());
+                            self.stack.push(Value::String(s));
+                        },
+                        _ => return Err("Expected a string builder")
+                    }
+                },
+                Instruction::NewArray(n) => {
+                    let n = *n as usize;
+                    if self.stack.len() < n {
+                        return Err("Not enough values on the stack");
+                    }
+                    self.check_collection_len(n)?;
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(Value::Array(Rc::new(RefCell::new(items))));
+                },
+                Instruction::IndexGet => {
+                    let id = self.pop_index()?;
+                    match self.pop_stack()? {
+                        Value::Array(arr) => {
+                            let arr = arr.borrow();
+                            let item = arr[normalize_index(arr.len(), id)?].clone();
+                            self.stack.push(item);
+                        },
+                        _ => return Err("Expected an array")
+                    }
+                },
+                Instruction::IndexSet => {
+                    let val = self.pop_stack()?;
+                    let id = self.pop_index()?;
+                    let target = self.pop_stack()?;
+                    if val.contains_collection(&target) {
+                        return Err("A collection can't contain itself");
+                    }
+                    match target {
+                        Value::Array(arr) => {
+                            let mut arr = arr.borrow_mut();
+                            let id = normalize_index(arr.len(), id)?;
+                            arr[id] = val.clone();
+                        },
+                        _ => return Err("Expected an array")
+                    }
+                    self.stack.push(val);
+                },
+                Instruction::SliceSet => {
+                    let replacement_val = self.pop_stack()?;
+                    let replacement = match &replacement_val {
+                        //copied first, the replacement may be the array itself
+                        Value::Array(items) => items.borrow().clone(),
+                        _ => return Err("Expected an array")
+                    };
+                    let end = self.pop_bound()?;
+                    let start = self.pop_bound()?;
+                    let target = self.pop_stack()?;
+                    if replacement.iter().any(|item| item.contains_collection(&target)) {
+                        return Err("A collection can't contain itself");
+                    }
+                    match target {
+                        Value::Array(arr) => {
+                            let mut arr = arr.borrow_mut();
+                            let len = arr.len();
+                            let start = start.map_or(Ok(0), |i| normalize_bound(len, i))?;
+                            let end = end.map_or(Ok(len), |i| normalize_bound(len, i))?;
+                            if start > end {
+                                return Err("Slice range out of bounds");
                             }
-                        }
-                    },
-                    Instruction::Divide => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        match (a, b) {
-                            (Value::Number(n1), Value::Number(n2)) => {
-                                if n2 == 0. {
-                                    return Err("Cannot divide by 0");
-                                }
-                                self.stack.push(Value::Number(n1 / n2));
-                            },
-                            _ => {
-                                return Err("The division operator can only be used with numbers");
+                            self.check_collection_len(len - (end - start) + replacement.len())?;
+                            arr.splice(start..end, replacement.iter().cloned());
+                        },
+                        _ => return Err("Expected an array")
+                    }
+                    self.stack.push(replacement_val);
+                },
+                Instruction::Clear => {
+                    match self.pop_stack()? {
+                        Value::Array(arr) => arr.borrow_mut().clear(),
+                        Value::Map(map) => map.borrow_mut().clear(),
+                        _ => return Err("Only arrays and maps can be cleared")
+                    }
+                    self.stack.push(Value::Null);
+                },
+                Instruction::NewMap(n) => {
+                    let n = *n as usize;
+                    if self.stack.len() < n * 2 {
+                        return Err("Not enough values on the stack");
+                    }
+                    let mut map = Map::new();
+                    let pairs = self.stack.split_off(self.stack.len() - n * 2);
+                    for pair in pairs.chunks(2) {
+                        map.insert(MapKey::from_value(&pair[0])?, pair[1].clone());
+                    }
+                    self.check_collection_len(map.len())?;
+                    self.stack.push(Value::Map(Rc::new(RefCell::new(map))));
+                },
+                Instruction::MapGet => {
+                    let key = MapKey::from_value(&self.pop_stack()?)?;
+                    match self.pop_stack()? {
+                        Value::Map(map) => {
+                            let val = map.borrow().get(&key).cloned().unwrap_or(Value::Null);
+                            self.stack.push(val);
+                        },
+                        _ => return Err("Expected a map")
+                    }
+                },
+                Instruction::MapSet => {
+                    let val = self.pop_stack()?;
+                    let key = MapKey::from_value(&self.pop_stack()?)?;
+                    let target = self.pop_stack()?;
+                    if val.contains_collection(&target) {
+                        return Err("A collection can't contain itself");
+                    }
+                    match target {
+                        Value::Map(map) => {
+                            let mut map = map.borrow_mut();
+                            if !map.contains_key(&key) {
+                                self.check_collection_len(map.len() + 1)?;
                             }
-                        }
-                    },
-                    Instruction::Equal => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        self.stack.push(Value::Bool(a.is_equal_to(&b)));
-                    },
-                    Instruction::NotEqual => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        self.stack.push(Value::Bool(!a.is_equal_to(&b)));
-                    },
-                    Instruction::GreaterEqual => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        self.stack.push(Value::Bool(a.is_equal_to(&b) || a.is_greater_than(&b)));
-                    },
-                    Instruction::LessEqual => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        self.stack.push(Value::Bool(a.is_equal_to(&b) || b.is_greater_than(&a)));
-                    },
-                    Instruction::Greater => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        self.stack.push(Value::Bool(a.is_greater_than(&b)));
-                    },
-                    Instruction::Less => {
-                        let b = self.pop_stack();
-                        let a = self.pop_stack();
-
-                        self.stack.push(Value::Bool(b.is_greater_than(&a)));
-                    },
-                    Instruction::Trace => {
-                        let a = self.pop_stack();
-
-                        let val = a.to_string().unwrap_or("".to_string());
-                        //add filename
-                        let line_no = self.chk.get_line_no(self.cur_instr as u32);
-                        println!("[{}] {}", line_no, val);
-                        //maybe don't pop at all?
-
-                        self.stack.push(a);
-                    },
-                    Instruction::Pop => {
-                        self.pop_stack();
-                    },
-                    Instruction::PushVariable(id) => {
-                        let id = *id;
-                        let var = self.get_stack(id).clone();
-                        self.stack.push(var);
-                    },
-                    Instruction::Assign(id) => {
-                        let id = *id;
-                        let val = self.get_stack_top().clone();
-                        self.set_stack(id, val);
-                    },
-                    Instruction::JumpIfFalse(jump_count) => {
-                        let jump_count = *jump_count as usize;
-                        let val = self.pop_stack();
-                        if !val.is_truthy() {
-                            self.cur_instr += jump_count;
-                        }
-                    },
-                    Instruction::Jump(jump_count) => {
-                        let jump_count = *jump_count as usize;
-                        self.cur_instr += jump_count;
+                            map.insert(key, val.clone());
+                        },
+                        _ => return Err("Expected a map")
+                    }
+                    self.stack.push(val);
+                },
+                Instruction::Pop => {
+                    self.pop_stack()?;
+                },
+                Instruction::Dup => {
+                    if self.stack.is_empty() {
+                        return Err("Not enough values on the stack");
                     }
+                    let top = self.get_stack_top()?.clone();
+                    self.stack.push(top);
+                },
+                Instruction::DupN(n) => {
+                    let n = *n as usize;
+                    if self.stack.len() < n {
+                        return Err("Not enough values on the stack");
+                    }
+                    let from = self.stack.len() - n;
+                    self.stack.extend_from_within(from..);
+                },
+                Instruction::Swap => {
+                    if self.stack.len() < 2 {
+                        return Err("Not enough values on the stack");
+                    }
+                    let n = self.stack.len();
+                    self.stack.swap(n - 1, n - 2);
+                },
+                Instruction::Nop => {},
+                Instruction::GetType => {
+                    let val = self.pop_stack()?;
+                    self.stack.push(Value::String(val.type_name().to_string()));
+                },
+                Instruction::PushVariable(id) => {
+                    let id = *id;
+                    let var = self.get_stack(id)?.clone();
+                    self.stack.push(var);
+                },
+                Instruction::Assign(id) => {
+                    let id = *id;
+                    let val = self.get_stack_top()?.clone();
+                    self.set_stack(id, val)?;
+                },
+                Instruction::Increment(id) | Instruction::Decrement(id) => {
+                    let id = *id;
+                    let step = if matches!(next, Instruction::Increment(_)) {1} else {-1};
 
-                    #[allow(unreachable_patterns)]
-                    _ => unimplemented!()
-                };
-            }
-            else {break};
+                    let res = self.get_stack(id)?.arith(&Value::Int(step), i64::checked_add, |x, y| x + y)
+                        .ok_or("Only numbers can be incremented or decremented")?;
+                    self.set_stack(id, res)?;
+                },
+                Instruction::GetGlobal(id) => {
+                    let id = *id;
+                    let val = self.globals.get(&id).ok_or("Undefined global")?.clone();
+                    self.stack.push(val);
+                },
+                Instruction::SetGlobal(id) => {
+                    let id = *id;
+                    let val = self.get_stack_top()?.clone();
+                    self.globals.insert(id, val);
+                },
+                Instruction::JumpIfFalse(jump_count) => {
+                    let jump_count = *jump_count as usize;
+                    let val = self.pop_stack()?;
+                    if !val.is_truthy() {
+                        self.cur_instr = self.jump_target(jump_count)?;
+                    }
+                },
+                Instruction::Jump(jump_count) => {
+                    let jump_count = *jump_count as usize;
+                    self.cur_instr = self.jump_target(jump_count)?;
+                },
+                Instruction::And(jump_count) | Instruction::Or(jump_count) => {
+                    let jump_count = *jump_count as usize;
+                    let is_or = matches!(next, Instruction::Or(_));
+                    // a falsy left side decides an and, a truthy one an or
+                    let decides = self.get_stack_top()?.is_truthy() == is_or;
+                    if decides {
+                        self.cur_instr = self.jump_target(jump_count)?;
+                    }
+                    else {
+                        self.pop_stack()?;
+                    }
+                },
+                Instruction::SwitchType(arms) => {
+                    let type_name = self.stack.last().ok_or("Not enough values on the stack")?.type_name();
+                    if let Some((_, offset)) = arms.iter().find(|(name, _)| *name == type_name) {
+                        self.cur_instr = self.jump_target(*offset as usize)?;
+                    }
+                },
+                Instruction::PushTryHandler(jump_count) => {
+                    let jump_count = *jump_count as usize;
+                    let catch = self.jump_target(jump_count)?;
+                    let height = self.stack.len();
+                    self.handlers.push(TryHandler {catch, height});
+                },
+                Instruction::PopTryHandler => {
+                    self.handlers.pop().ok_or("No try handler to pop")?;
+                },
+                Instruction::Loop(jump_count) => {
+                    let jump_count = *jump_count as usize;
+                    self.cur_instr = self.cur_instr.checked_sub(jump_count)
+                        .ok_or("Jump out of bounds")?;
+                },
+                //the compiler always patches these, so one left over means the bytecode is broken
+                Instruction::JumpPlaceholder => {
+                    return Err("Unpatched jump placeholder");
+                }
+            };
         }
+        else {return Ok(self.halted())};
 
-        Ok(())
+        Ok(StepResult::Continue)
+    }
+
+}
+
+// configures a VM in one expression, for embedders, e.g.
+// VM::builder().output(Box::new(buf)).instruction_limit(10_000).build()
+pub struct VmBuilder {
+    debug: bool,
+    out: Option<Box<dyn Write>>,
+    trace_format: TraceFormat,
+    float_precision: Option<usize>,
+    strict: bool,
+    instr_limit: Option<u64>,
+    timeout: Option<Duration>,
+    collection_limit: Option<usize>,
+    profiling: bool
+}
+
+impl VmBuilder {
+    pub fn debug(mut self, debug: bool) -> VmBuilder {
+        self.debug = debug;
+        self
+    }
+    pub fn output(mut self, out: Box<dyn Write>) -> VmBuilder {
+        self.out = Some(out);
+        self
+    }
+    pub fn trace_format(mut self, format: TraceFormat) -> VmBuilder {
+        self.trace_format = format;
+        self
+    }
+    pub fn float_precision(mut self, digits: usize) -> VmBuilder {
+        self.float_precision = Some(digits);
+        self
+    }
+    pub fn strict(mut self, strict: bool) -> VmBuilder {
+        self.strict = strict;
+        self
+    }
+    pub fn instruction_limit(mut self, limit: u64) -> VmBuilder {
+        self.instr_limit = Some(limit);
+        self
+    }
+    pub fn timeout(mut self, timeout: Duration) -> VmBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn collection_limit(mut self, limit: usize) -> VmBuilder {
+        self.collection_limit = Some(limit);
+        self
+    }
+    pub fn profiling(mut self, profiling: bool) -> VmBuilder {
+        self.profiling = profiling;
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let out = self.out.unwrap_or_else(|| Box::new(io::stdout()));
+        let mut vm = VM::with_output(self.debug, out);
+        vm.trace_format = self.trace_format;
+        vm.float_precision = self.float_precision;
+        vm.strict = self.strict;
+        vm.instr_limit = self.instr_limit;
+        vm.timeout = self.timeout;
+        vm.collection_limit = self.collection_limit;
+        vm.set_profiling(self.profiling);
+        vm
     }
+}
+
+// what run_chunk sets up, anything left as None keeps the VM default
+#[derive(Default)]
+pub struct RunOptions {
+    pub output: Option<Box<dyn Write>>,
+    pub float_precision: Option<usize>,
+    pub instruction_limit: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub collection_limit: Option<usize>
+}
+
+// runs chk to completion on a VM of its own, for embedders that only need
+// the result
+pub fn run_chunk(chk: Chunk, opts: RunOptions) -> Result<Value, RuntimeError> {
+    let mut builder = VM::builder();
+    if let Some(out) = opts.output {
+        builder = builder.output(out);
+    }
+    if let Some(digits) = opts.float_precision {
+        builder = builder.float_precision(digits);
+    }
+    if let Some(limit) = opts.instruction_limit {
+        builder = builder.instruction_limit(limit);
+    }
+    if let Some(timeout) = opts.timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(limit) = opts.collection_limit {
+        builder = builder.collection_limit(limit);
+    }
+    builder.build().execute(chk)
+}
 
+impl Drop for VM {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
 }
 
 #[cfg(test)]
@@ -266,72 +1229,1681 @@ mod tests {
         chk.add_const(Value::Number(5.));
         chk.add_instr(Instruction::PushConstant(0), 0);
         chk.add_instr(Instruction::Negate, 0);
-        chk.add_instr(Instruction::Return, 0);
+        chk.add_instr(Instruction::Halt, 0);
 
         let mut vm = VM::new(true);
 
-        assert_eq!(vm.execute(chk), Ok(()));
+        assert!(vm.execute(chk).is_ok());
     }
 
-    #[test]
-    fn test_arithmetic() {
+    fn arithmetic_chunk() -> Chunk {
         let mut chk = Chunk::new();
         
         //5 - 5 / 2.5 + 1 * 2 = 5
-        //consts[0] = 5, consts[1] = 5, consts[2] = 2.5, consts[3] = 1, consts[4] = 2
+        //consts[0] = 5, consts[1] = 2.5, consts[2] = 1, consts[3] = 2
+        //push 0
         //push 0
         //push 1
-        //push 2
         //divide
         //subtract
+        //push 2
         //push 3
-        //push 4
         //multiply
         //add
         chk.add_const(Value::Number(5.));
         chk.add_instr(Instruction::PushConstant(0), 0);
 
         chk.add_const(Value::Number(5.));
-        chk.add_instr(Instruction::PushConstant(1), 0);
+        chk.add_instr(Instruction::PushConstant(0), 0);
 
         chk.add_const(Value::Number(2.5));
-        chk.add_instr(Instruction::PushConstant(2), 0);
+        chk.add_instr(Instruction::PushConstant(1), 0);
 
         chk.add_instr(Instruction::Divide, 0);
 
         chk.add_instr(Instruction::Subtract, 0);
 
         chk.add_const(Value::Number(1.));
-        chk.add_instr(Instruction::PushConstant(3), 0);
+        chk.add_instr(Instruction::PushConstant(2), 0);
 
         chk.add_const(Value::Number(2.));
-        chk.add_instr(Instruction::PushConstant(4), 0);
+        chk.add_instr(Instruction::PushConstant(3), 0);
 
         chk.add_instr(Instruction::Multiply, 0);
 
         chk.add_instr(Instruction::Add, 0);
 
-        chk.add_instr(Instruction::Return, 0);
+        chk.add_instr(Instruction::Halt, 0);
+        chk
+    }
+
+    // runs what build adds to a new chunk, followed by a Halt, on a VM
+    // without debug output. Gives the stack it halted with or the error message
+    fn run_built(build: impl FnOnce(&mut Chunk)) -> Result<Vec<Value>, String> {
+        let mut chk = Chunk::new();
+        build(&mut chk);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        vm.execute(chk).map(|_| vm.stack.clone()).map_err(|err| err.message)
+    }
 
+    #[test]
+    fn test_arithmetic() {
         let mut vm = VM::new(true);
 
-        assert_eq!(vm.execute(chk), Ok(()));
+        assert!(vm.execute(arithmetic_chunk()).is_ok());
+        assert_eq!(vm.stack, vec![Value::Number(5.)]);
     }
 
     #[test]
-    fn test_kwexpr() {
+    fn test_result_value() {
         let mut chk = Chunk::new();
-        chk.add_line(0);
+
+        chk.add_push_const(Value::Number(2.), 0);
+        chk.add_push_const(Value::Number(3.), 0);
+        chk.add_instr(Instruction::Add, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        assert_eq!(vm.execute(chk), Ok(Value::Number(5.)));
+
+        let mut chk = Chunk::new();
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        assert_eq!(vm.execute(chk), Ok(Value::Null));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::new(false);
+        vm.reset(arithmetic_chunk());
+
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.stack, vec![Value::Number(5.)]);
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.stack, vec![Value::Number(5.), Value::Number(5.), Value::Number(2.5)]);
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.stack, vec![Value::Number(5.), Value::Number(2.)]);
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.stack, vec![Value::Number(3.)]);
+
+        while vm.step() == Ok(StepResult::Continue) {}
+        assert_eq!(vm.stack, vec![Value::Number(5.)]);
+        assert_eq!(vm.step(), Ok(StepResult::Halted(Value::Number(5.))));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = VM::new(false);
+        vm.set_profiling(true);
+
+        vm.reset(arithmetic_chunk());
+        assert_eq!(vm.resume(), Ok(StepResult::Halted(Value::Number(5.))));
+
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::String("queso".to_string()), 0);
+        chk.add_instr(Instruction::StrLen, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        vm.reset(chk);
+        assert_eq!(vm.resume(), Ok(StepResult::Halted(Value::Int(5))));
+        assert_eq!(vm.stack, vec![Value::Int(5)]);
+        assert_eq!(vm.instructions_executed(), 3);
+        assert_eq!(vm.profile().unwrap().get("Add"), None);
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut vm = VM::new(false);
+        assert!(vm.profile().is_none());
+
+        vm.set_profiling(true);
+        assert!(vm.execute(arithmetic_chunk()).is_ok());
+
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.get("Add"), Some(&1));
+        assert_eq!(profile.get("Multiply"), Some(&1));
+        assert_eq!(profile.get("PushConstant"), Some(&5));
+        assert_eq!(profile.get("Halt"), Some(&1));
+        assert_eq!(profile.get("Nop"), None);
+    }
+
+    #[test]
+    fn test_jump_bounds() {
+        let run = |instrs: Vec<Instruction>| run_built(|chk| {
+            for instr in instrs {
+                chk.add_instr(instr, 0);
+            }
+        });
+        let out_of_bounds = Err("Jump out of bounds".to_string());
+
+        assert_eq!(run(vec![Instruction::Jump(5)]), out_of_bounds);
+        assert_eq!(run(vec![Instruction::Jump(1)]), out_of_bounds);
+        assert_eq!(run(vec![Instruction::PushFalse, Instruction::JumpIfFalse(2)]), out_of_bounds);
+        assert_eq!(run(vec![Instruction::PushFalse, Instruction::And(9)]), out_of_bounds);
+        assert_eq!(run(vec![Instruction::PushTryHandler(3)]), out_of_bounds);
+        assert_eq!(run(vec![Instruction::Loop(3)]), out_of_bounds);
+
+        //landing on the last instruction is fine
+        assert_eq!(run(vec![Instruction::Jump(1), Instruction::PushTrue]), Ok(vec![]));
+        assert_eq!(run(vec![Instruction::PushTrue, Instruction::JumpIfFalse(9)]), Ok(vec![]));
+
+        assert_eq!(run(vec![Instruction::JumpPlaceholder]), Err("Unpatched jump placeholder".to_string()));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let run_with = |instr: Instruction, debug: bool| {
+            let mut chk = Chunk::new();
+            chk.add_instr(instr, 4);
+            chk.add_instr(Instruction::Halt, 4);
+
+            let mut vm = VM::new(debug);
+            vm.execute(chk)
+        };
+        let run = |instr: Instruction| run_with(instr, false);
+
+        assert_eq!(run(Instruction::Add), Err(RuntimeError::new("Stack underflow", 4)));
+        assert_eq!(run(Instruction::Negate), Err(RuntimeError::new("Stack underflow", 4)));
+        assert_eq!(run(Instruction::PushVariable(3)), Err(RuntimeError::new("Stack slot out of range", 4)));
+
+        let out_of_range = Err(RuntimeError::new("Constant out of range", 4));
+        assert_eq!(run(Instruction::PushConstant(9)), out_of_range);
+        assert_eq!(run(Instruction::PushConstantLong(9)), out_of_range);
+        assert_eq!(run(Instruction::PushSymbol(9)), out_of_range);
+        //the debug trace prints the constant before the instruction runs
+        assert_eq!(run_with(Instruction::PushConstant(9), true), out_of_range);
+        assert_eq!(run_with(Instruction::PushConstantLong(9), true), out_of_range);
+
+        let mut chk = Chunk::new();
+        chk.add_instr(Instruction::PushTrue, 4);
+        chk.add_instr(Instruction::TraceLabeled(9), 4);
+        chk.add_instr(Instruction::Halt, 4);
+        assert_eq!(VM::new(false).execute(chk), out_of_range);
+    }
+
+    #[test]
+    fn test_operand_order() {
+        let mut chk = Chunk::new();
+
+        //5 - 2, 1 < 2
+        chk.add_const(Value::Number(5.));
+        chk.add_const(Value::Number(2.));
+        chk.add_const(Value::Number(1.));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+        chk.add_instr(Instruction::PushConstant(1), 0);
+        chk.add_instr(Instruction::Subtract, 0);
+        chk.add_instr(Instruction::PushConstant(2), 0);
+        chk.add_instr(Instruction::PushConstant(1), 0);
+        chk.add_instr(Instruction::Less, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::Number(3.), Value::Bool(true)]);
+    }
+
+    #[test]
+    fn test_runtime_error_line() {
+        let mut chk = Chunk::new();
+
+        //1 / 0 on line 3
+        chk.add_const(Value::Number(1.));
+        chk.add_const(Value::Number(0.));
+        chk.add_instr(Instruction::PushConstant(0), 1);
+        chk.add_instr(Instruction::PushConstant(1), 2);
+        chk.add_instr(Instruction::Divide, 3);
+        chk.add_instr(Instruction::Halt, 4);
+
+        let mut vm = VM::new(false);
+
+        assert_eq!(vm.execute(chk), Err(RuntimeError::new("Cannot divide by 0", 3)));
+    }
+
+    #[test]
+    fn test_string_comparison() {
+        let run = |a: &str, b: Value, instr: Instruction| run_built(|chk| {
+            chk.add_push_const(Value::String(a.to_string()), 0);
+            chk.add_push_const(b, 0);
+            chk.add_instr(instr, 0);
+        });
+        let s = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(run("apple", s("banana"), Instruction::Less), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run("b", s("a"), Instruction::Greater), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run("b", s("b"), Instruction::GreaterEqual), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run("b", s("a"), Instruction::LessEqual), Ok(vec![Value::Bool(false)]));
+        assert_eq!(
+            run("1", Value::Number(1.), Instruction::Less),
+            Err("Only two numbers, strings or arrays can be compared".to_string())
+        );
+    }
+
+    #[test]
+    fn test_nan_comparison() {
+        let run = |instr: Instruction| run_built(|chk| {
+            //inf - inf is NaN
+            for _ in 0..2 {
+                chk.add_push_const(Value::Number(f64::INFINITY), 0);
+                chk.add_push_const(Value::Number(f64::INFINITY), 0);
+                chk.add_instr(Instruction::Subtract, 0);
+            }
+            chk.add_instr(instr, 0);
+        });
+
+        assert_eq!(run(Instruction::Equal), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(Instruction::NotEqual), Ok(vec![Value::Bool(false)]));
+        for instr in [Instruction::Less, Instruction::LessEqual, Instruction::Greater, Instruction::GreaterEqual].iter() {
+            assert_eq!(run(instr.clone()), Err("NaN cannot be ordered".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        let run = |a: f64, b: f64, epsilon: Value| run_built(|chk| {
+            chk.add_push_const(Value::Number(a), 0);
+            chk.add_push_const(Value::Number(b), 0);
+            chk.add_push_const(epsilon, 0);
+            chk.add_instr(Instruction::ApproxEq, 0);
+        });
+        let res = |b: bool| Ok(vec![Value::Bool(b)]);
+
+        assert_eq!(run(0.1 + 0.2, 0.3, Value::Null), res(true));
+        assert_eq!(run(1., 1. + DEFAULT_EPSILON / 2., Value::Null), res(true));
+        assert_eq!(run(1., 1. + DEFAULT_EPSILON * 2., Value::Null), res(false));
+        assert_eq!(run(1., 1.09, Value::Number(0.1)), res(true));
+        assert_eq!(run(1., 1.11, Value::Number(0.1)), res(false));
+        assert_eq!(run(1., 1., Value::Number(0.)), res(true));
+        assert_eq!(run(f64::NAN, f64::NAN, Value::Null), res(true));
+        assert_eq!(run(f64::INFINITY, f64::INFINITY, Value::Null), res(true));
+        assert_eq!(run(f64::NAN, 1., Value::Number(f64::INFINITY)), res(false));
+
+        assert_eq!(run(1., 2., Value::String("0.1".to_string())), Err("The tolerance has to be a number".to_string()));
+        assert_eq!(
+            run_built(|chk| {
+                chk.add_push_const(Value::String("1".to_string()), 0);
+                chk.add_push_const(Value::Int(1), 0);
+                chk.add_instr(Instruction::PushNull, 0);
+                chk.add_instr(Instruction::ApproxEq, 0);
+            }),
+            Err("Only numbers can be compared approximately".to_string())
+        );
+    }
+
+    #[test]
+    fn test_assert_eq() {
+        let assert_eq = |chk: &mut Chunk, actual: Value, expected: Value| {
+            chk.add_push_const(actual, 1);
+            chk.add_push_const(expected, 1);
+            chk.add_push_const(Value::String("sum".to_string()), 1);
+            chk.add_instr(Instruction::AssertEq, 1);
+        };
+        let run = |actual: Value, expected: Value| {
+            let mut chk = Chunk::new();
+            assert_eq(&mut chk, actual, expected);
+            chk.add_instr(Instruction::Halt, 1);
+            VM::new(false).execute(chk)
+        };
+
+        assert_eq!(run(Value::Int(5), Value::Int(5)), Ok(Value::Null));
+        assert_eq!(run(Value::Int(1), Value::Number(1.)), Ok(Value::Null));
+        assert_eq!(run(Value::Int(6), Value::Int(5)), Err(RuntimeError::new("expected 5 but got 6: sum", 1)));
+        assert_eq!(
+            run(Value::String("salsa".to_string()), Value::Number(0.5)),
+            Err(RuntimeError::new("expected 0.5 but got salsa: sum", 1))
+        );
+
+        //a try handler sees the same message
+        assert_eq!(run_built(|chk| {
+            chk.add_instr(Instruction::PushTryHandler(4), 0);
+            assert_eq(chk, Value::Int(6), Value::Int(5));
+        }), Ok(vec![Value::String("expected 5 but got 6: sum".to_string())]));
+    }
+
+    #[test]
+    fn test_in_range() {
+        let cases = [(5., true), (1., true), (10., true), (0., false), (11., false)];
+        for (x, res) in cases.iter() {
+            let mut chk = Chunk::new();
+
+            //1 <= x <= 10
+            chk.add_push_const(Value::Number(*x), 0);
+            chk.add_push_const(Value::Number(1.), 0);
+            chk.add_push_const(Value::Number(10.), 0);
+            chk.add_instr(Instruction::InRange, 0);
+            chk.add_instr(Instruction::Halt, 0);
+
+            let mut vm = VM::new(false);
+
+            assert!(vm.execute(chk).is_ok());
+            assert_eq!(vm.stack, vec![Value::Bool(*res)]);
+        }
+    }
+
+    #[test]
+    fn test_float_equality_warning() {
+        let make_chunk = |a: f64, b: f64, c: f64| {
+            let mut chk = Chunk::new();
+
+            //a + b == c
+            chk.add_const(Value::Number(a));
+            chk.add_const(Value::Number(b));
+            chk.add_const(Value::Number(c));
+            chk.add_instr(Instruction::PushConstant(0), 1);
+            chk.add_instr(Instruction::PushConstant(1), 1);
+            chk.add_instr(Instruction::Add, 1);
+            chk.add_instr(Instruction::PushConstant(2), 1);
+            chk.add_instr(Instruction::Equal, 1);
+            chk.add_instr(Instruction::Halt, 1);
+            chk
+        };
+
+        let mut vm = VM::new(false);
+        vm.set_strict(true);
+
+        assert!(vm.execute(make_chunk(0.1, 0.2, 0.3)).is_ok());
+        assert_eq!(vm.stack, vec![Value::Bool(false)]);
+        assert_eq!(vm.warnings(), ["[1] exact float comparison may be unreliable"]);
+
+        assert!(vm.execute(make_chunk(1., 2., 3.)).is_ok());
+        assert!(vm.warnings().is_empty());
+
+        let mut vm = VM::new(false);
+        assert!(vm.execute(make_chunk(0.1, 0.2, 0.3)).is_ok());
+        assert!(vm.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_int_arithmetic() {
+        let run = |a: Value, b: Value, instr: Instruction| run_built(|chk| {
+            chk.add_push_const(a, 0);
+            chk.add_push_const(b, 0);
+            chk.add_instr(instr, 0);
+        });
+
+        assert_eq!(run(Value::Int(2), Value::Int(3), Instruction::Add), Ok(vec![Value::Int(5)]));
+        assert_eq!(run(Value::Int(2), Value::Number(0.5), Instruction::Add), Ok(vec![Value::Number(2.5)]));
+        assert_eq!(run(Value::Int(6), Value::Int(3), Instruction::Divide), Ok(vec![Value::Int(2)]));
+        assert_eq!(run(Value::Int(7), Value::Int(2), Instruction::Divide), Ok(vec![Value::Number(3.5)]));
+        assert_eq!(run(Value::Int(-7), Value::Int(2), Instruction::Divide), Ok(vec![Value::Number(-3.5)]));
+        assert_eq!(run(Value::Int(i64::MIN), Value::Int(-1), Instruction::Divide), Ok(vec![Value::Number(-(i64::MIN as f64))]));
+        assert_eq!(run(Value::Int(7), Value::Int(0), Instruction::Divide), Err("Cannot divide by 0".to_string()));
+        assert_eq!(run(Value::Int(7), Value::Int(3), Instruction::Modulo), Ok(vec![Value::Int(1)]));
+        assert_eq!(run(Value::Int(2), Value::Int(10), Instruction::Exponent), Ok(vec![Value::Int(1024)]));
+        assert_eq!(run(Value::Int(2), Value::Int(-1), Instruction::Exponent), Ok(vec![Value::Number(0.5)]));
+        assert_eq!(run(Value::Int(i64::MAX), Value::Int(2), Instruction::Multiply), Ok(vec![Value::Number(i64::MAX as f64 * 2.)]));
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let run = |a: Value, b: Value, instr: Instruction| run_built(|chk| {
+            chk.add_push_const(a, 0);
+            chk.add_push_const(b, 0);
+            chk.add_instr(instr, 0);
+        });
+
+        assert_eq!(run(Value::Int(6), Value::Int(3), Instruction::BitAnd), Ok(vec![Value::Int(2)]));
+        assert_eq!(run(Value::Int(6), Value::Int(3), Instruction::BitOr), Ok(vec![Value::Int(7)]));
+        assert_eq!(run(Value::Int(6), Value::Int(3), Instruction::BitXor), Ok(vec![Value::Int(5)]));
+        assert_eq!(run(Value::Int(1), Value::Int(4), Instruction::Shl), Ok(vec![Value::Int(16)]));
+        assert_eq!(run(Value::Int(-16), Value::Int(2), Instruction::Shr), Ok(vec![Value::Int(-4)]));
+        assert_eq!(run(Value::Number(6.), Value::Int(3), Instruction::BitAnd), Ok(vec![Value::Int(2)]));
+
+        assert_eq!(
+            run(Value::Number(6.5), Value::Int(3), Instruction::BitAnd),
+            Err("Bitwise operators can only be used with integers".to_string())
+        );
+        assert_eq!(
+            run(Value::String("6".to_string()), Value::Int(3), Instruction::BitOr),
+            Err("Bitwise operators can only be used with integers".to_string())
+        );
+        assert_eq!(run(Value::Int(1), Value::Int(-1), Instruction::Shl), Err("Shift count has to be between 0 and 63".to_string()));
+        assert_eq!(run(Value::Int(1), Value::Int(64), Instruction::Shr), Err("Shift count has to be between 0 and 63".to_string()));
+    }
+
+    #[test]
+    fn test_power_of_two() {
+        let run = |val: Value, instr: Instruction| run_built(|chk| {
+            chk.add_push_const(val, 0);
+            chk.add_instr(instr, 0);
+        });
+
+        assert_eq!(run(Value::Int(64), Instruction::IsPowerOfTwo), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(Value::Int(63), Instruction::IsPowerOfTwo), Ok(vec![Value::Bool(false)]));
+        assert_eq!(run(Value::Number(1.), Instruction::IsPowerOfTwo), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(Value::Int(0), Instruction::IsPowerOfTwo), Ok(vec![Value::Bool(false)]));
+        assert_eq!(run(Value::Int(-8), Instruction::IsPowerOfTwo), Ok(vec![Value::Bool(false)]));
+
+        assert_eq!(run(Value::Int(8), Instruction::Log2), Ok(vec![Value::Int(3)]));
+        assert_eq!(run(Value::Number(1.), Instruction::Log2), Ok(vec![Value::Int(0)]));
+        assert_eq!(run(Value::Int(10), Instruction::Log2), Ok(vec![Value::Number(10f64.log2())]));
+        assert_eq!(run(Value::Int(0), Instruction::Log2), Err("log2 is only defined for positive numbers".to_string()));
+        assert_eq!(run(Value::Int(-4), Instruction::Log2), Err("log2 is only defined for positive numbers".to_string()));
+
+        assert_eq!(run(Value::Number(2.5), Instruction::Log2), Err("Expected an integer".to_string()));
+        assert_eq!(run(Value::String("8".to_string()), Instruction::IsPowerOfTwo), Err("Expected an integer".to_string()));
+    }
+
+    #[test]
+    fn test_modulo() {
+        let mut chk = Chunk::new();
+
+        //7 % 3 = 1
+        chk.add_const(Value::Number(7.));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+
+        chk.add_const(Value::Number(3.));
+        chk.add_instr(Instruction::PushConstant(1), 0);
+
+        chk.add_instr(Instruction::Modulo, 0);
+
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(true);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::Number(1.)]);
+
+        let mut chk = Chunk::new();
+        chk.add_const(Value::Number(7.));
+        chk.add_const(Value::Number(0.));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+        chk.add_instr(Instruction::PushConstant(1), 0);
+        chk.add_instr(Instruction::Modulo, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+
+        assert_eq!(vm.execute(chk).unwrap_err().message, "Cannot modulo by 0");
+    }
+
+    #[test]
+    fn test_exponent() {
+        let cases = [(2., 10., 1024.), (2., -1., 0.5), (9., 0.5, 3.)];
+        for (base, exp, res) in cases.iter() {
+            let mut chk = Chunk::new();
+
+            chk.add_const(Value::Number(*base));
+            chk.add_instr(Instruction::PushConstant(0), 0);
+
+            chk.add_const(Value::Number(*exp));
+            chk.add_instr(Instruction::PushConstant(1), 0);
+
+            chk.add_instr(Instruction::Exponent, 0);
+
+            chk.add_instr(Instruction::Halt, 0);
+
+            let mut vm = VM::new(false);
+
+            assert!(vm.execute(chk).is_ok());
+            assert_eq!(vm.stack, vec![Value::Number(*res)]);
+        }
+
+        let mut chk = Chunk::new();
+        chk.add_const(Value::Number(2.));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+        chk.add_instr(Instruction::PushTrue, 0);
+        chk.add_instr(Instruction::Exponent, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+
+        assert_eq!(vm.execute(chk).unwrap_err().message, "The exponentiation operator can only be used with numbers");
+    }
+
+    #[test]
+    fn test_strings() {
+        let run = |instrs: Vec<Instruction>, consts: Vec<Value>| run_built(|chk| {
+            for c in consts {
+                chk.add_const(c);
+            }
+            for instr in instrs {
+                chk.add_instr(instr, 0);
+            }
+        });
+        let s = Value::String("a🧀bc".to_string());
+        let num = |n: f64| Value::Number(n);
+
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::StrLen], vec![s.clone()]),
+            Ok(vec![Value::Int(4)])
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::CharAt], vec![s.clone(), num(1.)]),
+            Ok(vec![Value::String("🧀".to_string())])
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::PushConstant(2), Instruction::Substring],
+                vec![s.clone(), num(1.), num(3.)]),
+            Ok(vec![Value::String("🧀b".to_string())])
+        );
+
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::CharAt], vec![s.clone(), num(4.)]),
+            Err("Index out of bounds".to_string())
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::PushConstant(2), Instruction::Substring],
+                vec![s.clone(), num(2.), num(5.)]),
+            Err("Index out of bounds".to_string())
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::PushConstant(2), Instruction::Substring],
+                vec![s.clone(), num(3.), num(2.)]),
+            Err("Substring range out of bounds".to_string())
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::StrLen], vec![num(4.)]),
+            Err("Expected a string".to_string())
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::CharAt], vec![s, num(0.5)]),
+            Err("An index has to be an integer".to_string())
+        );
+    }
+
+    #[test]
+    fn test_format_str() {
+        let run = |template: &str, args: Vec<Value>| run_built(|chk| {
+            chk.add_push_const(Value::String(template.to_string()), 0);
+            let n = args.len() as u16;
+            for arg in args {
+                chk.add_push_const(arg, 0);
+            }
+            chk.add_instr(Instruction::FormatStr(n), 0);
+        });
+        let s = |s: &str| Value::String(s.to_string());
+        let res = |text: &str| Ok(vec![Value::String(text.to_string())]);
+
+        assert_eq!(run("{1} {0}, {1}!", vec![s("queso"), s("hola")]), res("hola queso, hola!"));
+        assert_eq!(run("{0} + {1} = {2}", vec![Value::Int(1), Value::Number(0.5), Value::Number(1.5)]), res("1 + 0.5 = 1.5"));
+        assert_eq!(run("{{{0}}} {{}}", vec![Value::Null]), res("{null} {}"));
+        assert_eq!(run("🧀", vec![Value::Bool(true)]), res("🧀"));
+
+        assert_eq!(run("{1}", vec![s("queso")]), Err("Placeholder index out of range".to_string()));
+        assert_eq!(run("{}", vec![s("queso")]), Err("A placeholder has to be an index in braces".to_string()));
+        assert_eq!(run("{0", vec![s("queso")]), Err("A placeholder has to be an index in braces".to_string()));
+        assert_eq!(run("{x}", vec![s("queso")]), Err("A placeholder has to be an index in braces".to_string()));
+        assert_eq!(run("}", vec![]), Err("Unmatched } in a format string, use }} for a literal one".to_string()));
+        assert_eq!(
+            run_built(|chk| chk.add_instr(Instruction::FormatStr(0), 0)),
+            Err("Not enough values on the stack".to_string())
+        );
+    }
+
+    #[test]
+    fn test_negative_indices() {
+        let run = |instrs: Vec<Instruction>, consts: Vec<Value>| run_built(|chk| {
+            for c in consts {
+                chk.add_const(c);
+            }
+            for instr in instrs {
+                chk.add_instr(instr, 0);
+            }
+        });
+        let char_at = |i: i64| run(
+            vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::CharAt],
+            vec![Value::String("queso".to_string()), Value::Int(i)]
+        );
+        let substring = |start: i64, end: i64| run(
+            vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::PushConstant(2), Instruction::Substring],
+            vec![Value::String("queso".to_string()), Value::Int(start), Value::Int(end)]
+        );
+        let s = |s: &str| Ok(vec![Value::String(s.to_string())]);
+
+        assert_eq!(char_at(-1), s("o"));
+        assert_eq!(char_at(-5), s("q"));
+        assert_eq!(substring(-3, -1), s("es"));
+        assert_eq!(substring(-5, 5), s("queso"));
+
+        assert_eq!(char_at(-6), Err("Index out of bounds".to_string()));
+        assert_eq!(substring(-6, 2), Err("Index out of bounds".to_string()));
+        assert_eq!(substring(0, -6), Err("Index out of bounds".to_string()));
+    }
+
+    #[test]
+    fn test_arrays() {
+        //[1, 2, 3] followed by whatever build adds
+        let run = |build: &dyn Fn(&mut Chunk)| run_built(|chk| {
+            for i in 1..=3 {
+                chk.add_push_const(Value::Int(i), 0);
+            }
+            chk.add_instr(Instruction::NewArray(3), 0);
+            build(chk);
+        });
+        let get = |chk: &mut Chunk, index: Value| {
+            chk.add_push_const(index, 0);
+            chk.add_instr(Instruction::IndexGet, 0);
+        };
+
+        assert_eq!(run(&|chk| get(chk, Value::Int(1))), Ok(vec![Value::Int(2)]));
+        assert_eq!(run(&|chk| get(chk, Value::Int(-3))), Ok(vec![Value::Int(1)]));
+        assert_eq!(run(&|chk| get(chk, Value::Int(3))), Err("Index out of bounds".to_string()));
+        assert_eq!(run(&|chk| get(chk, Value::Bool(true))), Err("An index has to be a number".to_string()));
+        assert_eq!(run(&|chk| {
+            chk.add_instr(Instruction::Pop, 0);
+            chk.add_push_const(Value::Int(1), 0);
+            get(chk, Value::Int(0));
+        }), Err("Expected an array".to_string()));
+        assert_eq!(run(&|chk| chk.add_instr(Instruction::NewArray(2), 0)), Err("Not enough values on the stack".to_string()));
+
+        //arr[-1] = 9 through a copy of arr is seen by the original
+        let stack = run(&|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(-1), 0);
+            chk.add_push_const(Value::Int(9), 0);
+            chk.add_instr(Instruction::IndexSet, 0);
+        }).unwrap();
+        assert_eq!(stack[1], Value::Int(9));
+        assert_eq!(stack[0].to_string(), Ok("[1, 2, 9]".to_string()));
+    }
+
+    #[test]
+    fn test_slice_set() {
+        //arr = [1, 2, 3, 4]; arr[start:end] = replacement; arr
+        let run = |start: Value, end: Value, replacement: &[i64]| run_built(|chk| {
+            for i in 1..=4 {
+                chk.add_push_const(Value::Int(i), 0);
+            }
+            chk.add_instr(Instruction::NewArray(4), 0);
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(start, 0);
+            chk.add_push_const(end, 0);
+            for i in replacement {
+                chk.add_push_const(Value::Int(*i), 0);
+            }
+            chk.add_instr(Instruction::NewArray(replacement.len() as u16), 0);
+            chk.add_instr(Instruction::SliceSet, 0);
+            chk.add_instr(Instruction::Pop, 0);
+        }).map(|stack| stack[0].to_string().unwrap());
+        let arr = |s: &str| Ok(s.to_string());
+
+        assert_eq!(run(Value::Int(1), Value::Int(3), &[7, 8, 9]), arr("[1, 7, 8, 9, 4]"));
+        assert_eq!(run(Value::Int(-3), Value::Int(-1), &[7, 8, 9]), arr("[1, 7, 8, 9, 4]"));
+        assert_eq!(run(Value::Int(1), Value::Int(3), &[7]), arr("[1, 7, 4]"));
+        assert_eq!(run(Value::Int(4), Value::Null, &[5, 6]), arr("[1, 2, 3, 4, 5, 6]"));
+        assert_eq!(run(Value::Null, Value::Null, &[]), arr("[]"));
+        assert_eq!(run(Value::Int(3), Value::Int(1), &[]), Err("Slice range out of bounds".to_string()));
+        assert_eq!(run(Value::Int(0), Value::Int(5), &[]), Err("Index out of bounds".to_string()));
+
+        //arr[0:0] = arr
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(1), 0);
+        chk.add_push_const(Value::Int(2), 0);
+        chk.add_instr(Instruction::NewArray(2), 0);
+        chk.add_instr(Instruction::Dup, 0);
+        chk.add_push_const(Value::Int(0), 0);
+        chk.add_push_const(Value::Int(0), 0);
+        chk.add_instr(Instruction::DupN(3), 0);
+        chk.add_instr(Instruction::Pop, 0);
+        chk.add_instr(Instruction::Pop, 0);
+        chk.add_instr(Instruction::SliceSet, 0);
+        chk.add_instr(Instruction::Halt, 0);
+        assert_eq!(VM::new(false).execute(chk).map(|arr| arr.to_string().unwrap()), Ok("[1, 2, 1, 2]".to_string()));
+    }
+
+    #[test]
+    fn test_clear() {
+        //mut a = <collection>; mut b = a; clear(b); a
+        let run = |new: Instruction, len: i64| run_built(|chk| {
+            for i in 0..len {
+                chk.add_push_const(Value::Int(i), 0);
+            }
+            chk.add_instr(new, 0);
+            chk.add_instr(Instruction::PushVariable(0), 1);
+            chk.add_instr(Instruction::PushVariable(1), 2);
+            chk.add_instr(Instruction::Clear, 2);
+            chk.add_instr(Instruction::Pop, 2);
+            chk.add_instr(Instruction::PushVariable(0), 3);
+        }).map(|stack| (stack[2].to_string().unwrap(), stack[1].to_string().unwrap()));
+
+        assert_eq!(run(Instruction::NewArray(3), 3), Ok(("[]".to_string(), "[]".to_string())));
+        assert_eq!(run(Instruction::NewMap(2), 4), Ok(("{}".to_string(), "{}".to_string())));
+        assert_eq!(run(Instruction::PushNull, 0), Err("Only arrays and maps can be cleared".to_string()));
+    }
+
+    #[test]
+    fn test_collection_cycles() {
+        //a = [[0]] or {0: [0]}; build stores into it
+        let run = |new: Instruction, build: &dyn Fn(&mut Chunk)| run_built(|chk| {
+            if let Instruction::NewMap(_) = new {
+                chk.add_push_const(Value::Int(0), 0);
+            }
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::NewArray(1), 0);
+            chk.add_instr(new, 0);
+            build(chk);
+            chk.add_instr(Instruction::Pop, 0);
+        }).map(|stack| stack[0].to_string().unwrap());
+        let cycle = Err("A collection can't contain itself".to_string());
+
+        //a[0] = a
+        assert_eq!(run(Instruction::NewArray(1), &|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_instr(Instruction::IndexSet, 0);
+        }), cycle);
+        //a[0][0] = a
+        assert_eq!(run(Instruction::NewArray(1), &|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::IndexGet, 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_instr(Instruction::IndexSet, 0);
+        }), cycle);
+        //a[0][0:0] = [a]
+        assert_eq!(run(Instruction::NewArray(1), &|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::IndexGet, 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_instr(Instruction::NewArray(1), 0);
+            chk.add_instr(Instruction::SliceSet, 0);
+        }), cycle);
+        //a[1] = a
+        assert_eq!(run(Instruction::NewMap(1), &|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(1), 0);
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_instr(Instruction::MapSet, 0);
+        }), cycle);
+        //a[1] = a[0], the same array twice isn't a cycle
+        assert_eq!(run(Instruction::NewMap(1), &|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(1), 0);
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::MapGet, 0);
+            chk.add_instr(Instruction::MapSet, 0);
+        }), Ok("{0: [0], 1: [0]}".to_string()));
+    }
+
+    #[test]
+    fn test_maps() {
+        //{"queso": 1} followed by whatever build adds
+        let run = |build: &dyn Fn(&mut Chunk)| run_built(|chk| {
+            chk.add_push_const(Value::String("queso".to_string()), 0);
+            chk.add_push_const(Value::Int(1), 0);
+            chk.add_instr(Instruction::NewMap(1), 0);
+            build(chk);
+        });
+        let get = |chk: &mut Chunk, key: Value| {
+            chk.add_push_const(key, 0);
+            chk.add_instr(Instruction::MapGet, 0);
+        };
+        let set = |chk: &mut Chunk, key: Value, val: Value| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(key, 0);
+            chk.add_push_const(val, 0);
+            chk.add_instr(Instruction::MapSet, 0);
+            chk.add_instr(Instruction::Pop, 0);
+        };
+
+        assert_eq!(run(&|chk| get(chk, Value::String("queso".to_string()))), Ok(vec![Value::Int(1)]));
+        assert_eq!(run(&|chk| get(chk, Value::String("nachos".to_string()))), Ok(vec![Value::Null]));
+        assert_eq!(run(&|chk| {
+            set(chk, Value::Int(2), Value::Bool(true));
+            get(chk, Value::Number(2.));
+        }), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(&|chk| {
+            set(chk, Value::String("queso".to_string()), Value::Int(5));
+            get(chk, Value::String("queso".to_string()));
+        }), Ok(vec![Value::Int(5)]));
+
+        assert_eq!(run(&|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            get(chk, Value::Null);
+            get(chk, Value::Null);
+        }), Err("Expected a map".to_string()));
+        assert_eq!(run(&|chk| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_instr(Instruction::MapGet, 0);
+        }), Err("Map keys can only be numbers, strings, bools or null".to_string()));
+    }
+
+    #[test]
+    fn test_collection_limit() {
+        let run = |build: &dyn Fn(&mut Chunk)| {
+            let mut chk = Chunk::new();
+            build(&mut chk);
+            chk.add_instr(Instruction::Halt, 0);
+
+            let mut vm = VM::builder().collection_limit(2).build();
+            vm.execute(chk).map(|val| val.type_name()).map_err(|err| err.message)
+        };
+        let push_ints = |chk: &mut Chunk, n: i64| {
+            for i in 0..n {
+                chk.add_push_const(Value::Int(i), 0);
+            }
+        };
+        let exceeded = Err("Collection size limit exceeded".to_string());
+
+        assert_eq!(run(&|chk| {
+            push_ints(chk, 2);
+            chk.add_instr(Instruction::NewArray(2), 0);
+        }), Ok("array"));
+        assert_eq!(run(&|chk| {
+            push_ints(chk, 3);
+            chk.add_instr(Instruction::NewArray(3), 0);
+        }), exceeded);
+        assert_eq!(run(&|chk| {
+            push_ints(chk, 6);
+            chk.add_instr(Instruction::NewMap(3), 0);
+        }), exceeded);
+
+        //{0: 1}, overwriting a key is fine, a third key isn't
+        let set = |chk: &mut Chunk, key: i64| {
+            chk.add_instr(Instruction::Dup, 0);
+            chk.add_push_const(Value::Int(key), 0);
+            chk.add_instr(Instruction::PushNull, 0);
+            chk.add_instr(Instruction::MapSet, 0);
+            chk.add_instr(Instruction::Pop, 0);
+        };
+        assert_eq!(run(&|chk| {
+            push_ints(chk, 2);
+            chk.add_instr(Instruction::NewMap(1), 0);
+            set(chk, 0);
+            set(chk, 1);
+        }), Ok("map"));
+        assert_eq!(run(&|chk| {
+            push_ints(chk, 2);
+            chk.add_instr(Instruction::NewMap(1), 0);
+            set(chk, 1);
+            set(chk, 2);
+        }), exceeded);
+    }
+
+    #[test]
+    fn test_string_builder() {
+        //mut s = <start>; mut i = 1000; do { s <append> "ab"; i-- } while (i); s
+        let run = |builder: bool| {
+            let mut chk = Chunk::new();
+
+            if builder {
+                chk.add_instr(Instruction::SbNew, 0);
+            }
+            else {
+                chk.add_push_const(Value::String("".to_string()), 0);
+            }
+            chk.add_push_const(Value::Int(1000), 0);
+            chk.add_instr(Instruction::PushVariable(0), 1);
+            chk.add_push_const(Value::String("ab".to_string()), 1);
+            if builder {
+                chk.add_instr(Instruction::SbAppend, 1);
+            }
+            else {
+                chk.add_instr(Instruction::Add, 1);
+                chk.add_instr(Instruction::Assign(0), 1);
+            }
+            chk.add_instr(Instruction::Pop, 1);
+            chk.add_instr(Instruction::Decrement(1), 1);
+            chk.add_instr(Instruction::PushVariable(1), 1);
+            chk.add_instr(Instruction::JumpIfFalse(1), 1);
+            chk.add_instr(Instruction::Loop(if builder {8} else {9}), 1);
+            chk.add_instr(Instruction::PushVariable(0), 2);
+            if builder {
+                chk.add_instr(Instruction::SbFinish, 2);
+            }
+            chk.add_instr(Instruction::Halt, 2);
+
+            let mut vm = VM::new(false);
+            vm.execute(chk)
+        };
+
+        let built = run(true);
+        assert_eq!(built, Ok(Value::String("ab".repeat(1000))));
+        assert_eq!(built, run(false));
+
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(1), 0);
+        chk.add_push_const(Value::Int(2), 0);
+        chk.add_instr(Instruction::SbAppend, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        assert_eq!(vm.execute(chk).unwrap_err().message, "Expected a string builder");
+    }
+
+    #[test]
+    fn test_do_while() {
+        //mut i = start; do { i = i + 1 } while (i < 3)
+        let run = |start: f64| {
+            let mut chk = Chunk::new();
+
+            chk.add_const(Value::Number(start));
+            chk.add_const(Value::Number(1.));
+            chk.add_const(Value::Number(3.));
+            chk.add_instr(Instruction::PushConstant(0), 0);
+
+            //body
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_instr(Instruction::PushConstant(1), 0);
+            chk.add_instr(Instruction::Add, 0);
+            chk.add_instr(Instruction::Assign(0), 0);
+            chk.add_instr(Instruction::Pop, 0);
+
+            //condition
+            chk.add_instr(Instruction::PushVariable(0), 0);
+            chk.add_instr(Instruction::PushConstant(2), 0);
+            chk.add_instr(Instruction::Less, 0);
+            chk.add_instr(Instruction::JumpIfFalse(1), 0);
+            chk.add_instr(Instruction::Loop(10), 0);
+
+            chk.add_instr(Instruction::Halt, 0);
+
+            let mut vm = VM::new(false);
+            assert!(vm.execute(chk).is_ok());
+            vm.stack.clone()
+        };
+
+        assert_eq!(run(0.), vec![Value::Number(3.)]);
+        //the body runs once even though the condition never holds
+        assert_eq!(run(10.), vec![Value::Number(11.)]);
+    }
+
+    fn kwexpr_chunk() -> Chunk {
+        let mut chk = Chunk::new();
+        chk.add_line(0);
         
         chk.add_const(Value::Number(5.));
         chk.add_instr(Instruction::PushConstant(0), 0);
 
         chk.add_instr(Instruction::Trace, 0);
 
-        chk.add_instr(Instruction::Return, 0);
+        chk.add_instr(Instruction::Halt, 0);
+        chk
+    }
+
+    #[test]
+    fn test_while() {
+        //mut i = start; mut n = 0; while (i) { i--; n++ }; n
+        let run = |start: i64| {
+            let mut chk = Chunk::new();
+
+            chk.add_push_const(Value::Int(start), 0);
+            chk.add_push_const(Value::Int(0), 0);
+            chk.add_instr(Instruction::PushVariable(0), 1);
+            chk.add_instr(Instruction::JumpIfFalse(3), 1);
+            chk.add_instr(Instruction::Decrement(0), 2);
+            chk.add_instr(Instruction::Increment(1), 2);
+            chk.add_instr(Instruction::Loop(5), 2);
+            chk.add_instr(Instruction::PushVariable(1), 3);
+            chk.add_instr(Instruction::Halt, 3);
+
+            let mut vm = VM::new(false);
+            vm.execute(chk)
+        };
+
+        assert_eq!(run(3), Ok(Value::Int(3)));
+        assert_eq!(run(0), Ok(Value::Int(0)));
+    }
 
+    // cargo test --release bench_numeric_loop -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_numeric_loop() {
+        //mut i = n; mut sum = 0; while (i) { sum = sum + i * 2 - 1; i-- }; sum
+        let n = 1_000_000;
+        let mut chk = Chunk::new();
+
+        chk.add_push_const(Value::Int(n), 0);
+        chk.add_push_const(Value::Int(0), 0);
+        chk.add_instr(Instruction::PushVariable(0), 1);
+        chk.add_instr(Instruction::JumpIfFalse(11), 1);
+        chk.add_instr(Instruction::PushVariable(1), 2);
+        chk.add_instr(Instruction::PushVariable(0), 2);
+        chk.add_push_const(Value::Int(2), 2);
+        chk.add_instr(Instruction::Multiply, 2);
+        chk.add_instr(Instruction::Add, 2);
+        chk.add_push_const(Value::Int(1), 2);
+        chk.add_instr(Instruction::Subtract, 2);
+        chk.add_instr(Instruction::Assign(1), 2);
+        chk.add_instr(Instruction::Pop, 2);
+        chk.add_instr(Instruction::Decrement(0), 2);
+        chk.add_instr(Instruction::Loop(13), 2);
+        chk.add_instr(Instruction::PushVariable(1), 3);
+        chk.add_instr(Instruction::Halt, 3);
+
+        let started = Instant::now();
+        assert_eq!(VM::new(false).execute(chk), Ok(Value::Int(n * n)));
+        println!("{} iterations in {:?}", n, started.elapsed());
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(1), 0);
+        chk.add_instr(Instruction::Halt, 0);
+        chk.add_push_const(Value::Int(2), 1);
+
+        let mut vm = VM::new(false);
+        assert_eq!(vm.execute(chk), Ok(Value::Int(1)));
+        assert_eq!(vm.step(), Ok(StepResult::Halted(Value::Int(1))));
+
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(1), 0);
+        chk.add_instr(Instruction::Return, 1);
+
+        let mut vm = VM::new(false);
+        assert_eq!(vm.execute(chk), Err(RuntimeError::new("Cannot return outside of a function", 1)));
+    }
+
+    #[test]
+    fn test_kwexpr() {
         let mut vm = VM::new(true);
 
-        assert_eq!(vm.execute(chk), Ok(()));
+        assert!(vm.execute(kwexpr_chunk()).is_ok());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let buf = SharedBuf::default();
+        let mut vm = VM::with_output(false, Box::new(buf.clone()));
+        vm.add_breakpoint(0);
+        vm.reset(kwexpr_chunk());
+
+        assert_eq!(vm.step(), Ok(StepResult::BreakpointHit(0)));
+        assert_eq!(vm.stack, vec![]);
+        assert_eq!(vm.resume(), Ok(StepResult::Halted(Value::Number(5.))));
+        assert_eq!(buf.text(), "[0] 5\n");
+
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(1), 1);
+        chk.add_push_const(Value::Int(2), 2);
+        chk.add_instr(Instruction::Add, 2);
+        chk.add_instr(Instruction::Halt, 3);
+
+        vm.add_breakpoint(2);
+        vm.reset(chk.clone());
+        assert_eq!(vm.resume(), Ok(StepResult::BreakpointHit(2)));
+        assert_eq!(vm.stack, vec![Value::Int(1)]);
+        assert_eq!(vm.resume(), Ok(StepResult::Halted(Value::Int(3))));
+
+        vm.remove_breakpoint(2);
+        vm.reset(chk.clone());
+        assert_eq!(vm.resume(), Ok(StepResult::Halted(Value::Int(3))));
+
+        vm.add_breakpoint(2);
+        assert_eq!(vm.execute(chk), Ok(Value::Int(3)));
+
+        //mut i = 0; while (++i < 3) {} with the loop on one line breaks every iteration
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(0), 1);
+        chk.add_instr(Instruction::Increment(0), 2);
+        chk.add_instr(Instruction::PushVariable(0), 2);
+        chk.add_push_const(Value::Int(3), 2);
+        chk.add_instr(Instruction::Less, 2);
+        chk.add_instr(Instruction::JumpIfFalse(1), 2);
+        chk.add_instr(Instruction::Loop(6), 2);
+        chk.add_instr(Instruction::PushVariable(0), 3);
+        chk.add_instr(Instruction::Halt, 3);
+
+        vm.reset(chk);
+        for i in 0..3 {
+            assert_eq!(vm.resume(), Ok(StepResult::BreakpointHit(2)));
+            assert_eq!(vm.stack, vec![Value::Int(i)]);
+        }
+        assert_eq!(vm.resume(), Ok(StepResult::Halted(Value::Int(3))));
+    }
+
+    #[test]
+    fn test_push_constant_long() {
+        let mut chk = Chunk::new();
+        for i in 0..=u16::MAX as u32 + 1 {
+            chk.add_const(Value::Number(i as f64));
+        }
+        chk.add_push_const(Value::String("last".to_string()), 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        assert_eq!(chk.get_instr(0).clone(), Instruction::PushConstantLong(u16::MAX as u32 + 2));
+
+        let mut vm = VM::new(false);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::String("last".to_string())]);
+    }
+
+    #[test]
+    fn test_increment() {
+        //mut i = 3; do { i-- } while (i)
+        let mut chk = Chunk::new();
+
+        chk.add_push_const(Value::Int(3), 0);
+        chk.add_instr(Instruction::Decrement(0), 0);
+        chk.add_instr(Instruction::PushVariable(0), 0);
+        chk.add_instr(Instruction::JumpIfFalse(1), 0);
+        chk.add_instr(Instruction::Loop(4), 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        //3 is the only constant
+        assert_eq!(chk.clone().add_const(Value::Int(1)), 1);
+
+        let mut vm = VM::new(false);
+
+        assert_eq!(vm.execute(chk), Ok(Value::Int(0)));
+        assert_eq!(vm.stack, vec![Value::Int(0)]);
+
+        let run = |val: Value, instr: Instruction| run_built(|chk| {
+            chk.add_push_const(val, 0);
+            chk.add_instr(instr, 0);
+        });
+
+        assert_eq!(run(Value::Number(1.5), Instruction::Increment(0)), Ok(vec![Value::Number(2.5)]));
+        assert_eq!(run(Value::Int(i64::MAX), Instruction::Increment(0)), Ok(vec![Value::Number(i64::MAX as f64 + 1.)]));
+        assert_eq!(
+            run(Value::String("1".to_string()), Instruction::Increment(0)),
+            Err("Only numbers can be incremented or decremented".to_string())
+        );
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut chk = Chunk::new();
+
+        chk.add_push_const(Value::Int(5), 0);
+        chk.add_instr(Instruction::SetGlobal(3), 0);
+        chk.add_instr(Instruction::Pop, 0);
+        chk.add_instr(Instruction::GetGlobal(3), 1);
+        chk.add_instr(Instruction::GetGlobal(3), 1);
+        chk.add_instr(Instruction::Add, 1);
+        chk.add_instr(Instruction::Halt, 1);
+
+        let mut vm = VM::new(false);
+
+        assert_eq!(vm.execute(chk), Ok(Value::Int(10)));
+        assert_eq!(vm.stack, vec![Value::Int(10)]);
+
+        //globals outlive the chunk that set them
+        let mut chk = Chunk::new();
+        chk.add_instr(Instruction::GetGlobal(3), 0);
+        chk.add_instr(Instruction::GetGlobal(4), 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        vm.reset(chk);
+        assert_eq!(vm.resume().unwrap_err(), RuntimeError::new("Undefined global", 0));
+        assert_eq!(vm.stack, vec![Value::Int(5)]);
+    }
+
+    #[test]
+    fn test_eval_chunk() {
+        let mut vm = VM::new(false);
+
+        let mut chk = Chunk::new();
+        let x = chk.add_const(Value::String("x".to_string())) as u16;
+        chk.add_push_const(Value::Int(5), 0);
+        chk.add_instr(Instruction::SetGlobal(0), 0);
+        chk.add_instr(Instruction::PushSymbol(x), 0);
+        chk.add_instr(Instruction::SetGlobal(1), 0);
+        chk.add_instr(Instruction::Halt, 0);
+        assert_eq!(vm.eval_chunk(chk), Ok(vm.intern("x")));
+
+        //a line that fails halfway leaves nothing behind for the next one
+        let mut chk = Chunk::new();
+        chk.add_instr(Instruction::PushTrue, 0);
+        chk.add_instr(Instruction::GetGlobal(2), 0);
+        chk.add_instr(Instruction::Halt, 0);
+        assert_eq!(vm.eval_chunk(chk).unwrap_err().message, "Undefined global");
+
+        let mut chk = Chunk::new();
+        let x = chk.add_const(Value::String("x".to_string())) as u16;
+        chk.add_instr(Instruction::GetGlobal(0), 0);
+        chk.add_instr(Instruction::GetGlobal(1), 0);
+        chk.add_instr(Instruction::PushSymbol(x), 0);
+        chk.add_instr(Instruction::Equal, 0);
+        chk.add_instr(Instruction::Halt, 0);
+        assert_eq!(vm.eval_chunk(chk), Ok(Value::Bool(true)));
+        assert_eq!(vm.stack, vec![Value::Int(5), Value::Bool(true)]);
+    }
+
+    #[test]
+    fn test_dup_swap() {
+        let run = |instrs: Vec<Instruction>| run_built(|chk| {
+            chk.add_const(Value::Number(5.));
+            chk.add_const(Value::Number(2.));
+            for instr in instrs {
+                chk.add_instr(instr, 0);
+            }
+        });
+
+        //x + x
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::Dup, Instruction::Add]),
+            Ok(vec![Value::Number(10.)])
+        );
+        //2 - 5 with the operands pushed the other way around
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::Swap, Instruction::Subtract]),
+            Ok(vec![Value::Number(-3.)])
+        );
+
+        assert_eq!(run(vec![Instruction::Dup]), Err("Not enough values on the stack".to_string()));
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::Swap]),
+            Err("Not enough values on the stack".to_string())
+        );
+
+        let (five, two) = (Value::Number(5.), Value::Number(2.));
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::PushConstant(1), Instruction::DupN(2)]),
+            Ok(vec![five.clone(), two.clone(), five.clone(), two])
+        );
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::DupN(0)]),
+            Ok(vec![five])
+        );
+
+        //the top slots of a stack deeper than a u16 can index
+        let mut vm = VM::new(false);
+        vm.stack.resize(u16::MAX as usize + 1, Value::Null);
+        vm.stack.push(Value::Int(1));
+        vm.stack.push(Value::Int(2));
+        let mut chk = Chunk::new();
+        chk.add_instr(Instruction::Swap, 0);
+        chk.add_instr(Instruction::Halt, 0);
+        assert_eq!(vm.execute(chk), Ok(Value::Int(1)));
+        assert_eq!(vm.stack[vm.stack.len() - 2..], [Value::Int(2), Value::Int(1)][..]);
+
+        assert_eq!(
+            run(vec![Instruction::PushConstant(0), Instruction::DupN(2)]),
+            Err("Not enough values on the stack".to_string())
+        );
+    }
+
+    #[test]
+    fn test_nop() {
+        let mut chk = Chunk::new();
+
+        chk.add_const(Value::Number(5.));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+        chk.add_instr(Instruction::Nop, 0);
+        chk.add_instr(Instruction::Nop, 0);
+        chk.add_instr(Instruction::Negate, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::Number(-5.)]);
+        assert_eq!(vm.cur_instr, 5);
+    }
+
+    #[test]
+    fn test_and_or() {
+        let run = |left: Value, instr: Instruction, right: Value| run_built(|chk| {
+            chk.add_push_const(left, 0);
+            chk.add_instr(instr, 0);
+            chk.add_push_const(right, 0);
+        });
+        let s = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(run(Value::Bool(false), Instruction::And(1), s("x")), Ok(vec![Value::Bool(false)]));
+        assert_eq!(run(Value::Bool(true), Instruction::And(1), s("y")), Ok(vec![s("y")]));
+        assert_eq!(run(Value::Int(0), Instruction::And(1), s("y")), Ok(vec![Value::Int(0)]));
+        assert_eq!(run(Value::Bool(true), Instruction::Or(1), s("x")), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(Value::Bool(false), Instruction::Or(1), s("y")), Ok(vec![s("y")]));
+        assert_eq!(run(Value::Null, Instruction::Or(1), s("y")), Ok(vec![s("y")]));
+    }
+
+    #[test]
+    fn test_switch_type() {
+        let run = |val: Value| {
+            let mut chk = Chunk::new();
+
+            chk.add_push_const(val, 0);
+            chk.add_instr(Instruction::SwitchType(vec![("number", 3), ("string", 6)]), 0);
+            for arm in ["default", "number", "string"].iter() {
+                chk.add_instr(Instruction::Pop, 0);
+                chk.add_push_const(Value::String(arm.to_string()), 0);
+                chk.add_instr(Instruction::Halt, 0);
+            }
+
+            let mut vm = VM::new(false);
+            vm.execute(chk)
+        };
+        let s = |s: &str| Ok(Value::String(s.to_string()));
+
+        assert_eq!(run(Value::Int(1)), s("number"));
+        assert_eq!(run(Value::Number(1.5)), s("number"));
+        assert_eq!(run(Value::String("queso".to_string())), s("string"));
+        assert_eq!(run(Value::Null), s("default"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut chk = Chunk::new();
+        let x = chk.add_const(Value::String("x".to_string())) as u16;
+
+        chk.add_instr(Instruction::PushSymbol(x), 0);
+        chk.add_instr(Instruction::PushSymbol(x), 0);
+        chk.add_instr(Instruction::Equal, 0);
+        chk.add_instr(Instruction::PushSymbol(x), 0);
+        chk.add_instr(Instruction::PushConstant(x), 0);
+        chk.add_instr(Instruction::Equal, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::Bool(true), Value::Bool(false)]);
+
+        match (vm.intern("x"), vm.intern("x"), vm.intern("y")) {
+            (Value::Symbol(a), Value::Symbol(b), Value::Symbol(c)) => {
+                assert!(a == b && a != c);
+                assert!(std::ptr::eq(a.name(), b.name()));
+                assert_eq!(a.name(), "x");
+            },
+            _ => panic!("intern should return symbols")
+        }
+        assert_eq!(vm.intern("x").to_string(), Ok("x".to_string()));
+    }
+
+    #[test]
+    fn test_try_handler() {
+        let run = |divisor: i64, pop_first: bool| run_built(|chk| {
+            chk.add_push_const(Value::String("before".to_string()), 0);
+            if pop_first {
+                chk.add_instr(Instruction::PushTryHandler(0), 0);
+                chk.add_instr(Instruction::PopTryHandler, 0);
+            }
+            //catches at the PushConstant after the Jump
+            chk.add_instr(Instruction::PushTryHandler(5), 0);
+            if pop_first {
+                chk.add_instr(Instruction::PopTryHandler, 0);
+            }
+            chk.add_push_const(Value::Int(10), 1);
+            chk.add_push_const(Value::Int(divisor), 1);
+            chk.add_instr(Instruction::Divide, 1);
+            if !pop_first {
+                chk.add_instr(Instruction::PopTryHandler, 1);
+            }
+            chk.add_instr(Instruction::Jump(1), 1);
+            chk.add_push_const(Value::String("caught".to_string()), 2);
+        });
+        let s = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(run(0, false), Ok(vec![s("before"), s("Cannot divide by 0"), s("caught")]));
+        assert_eq!(run(2, false), Ok(vec![s("before"), Value::Int(5)]));
+        assert_eq!(run(0, true), Err("Cannot divide by 0".to_string()));
+    }
+
+    #[test]
+    fn test_get_type() {
+        let mut chk = Chunk::new();
+
+        chk.add_push_const(Value::Int(1), 0);
+        chk.add_instr(Instruction::GetType, 0);
+        chk.add_instr(Instruction::PushNull, 0);
+        chk.add_instr(Instruction::GetType, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::String("number".to_string()), Value::String("null".to_string())]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut chk = Chunk::new();
+        for _ in 0..CHECK_INTERVAL * 4 {
+            chk.add_instr(Instruction::Nop, 0);
+        }
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        vm.set_timeout(Duration::from_secs(60));
+        assert!(vm.execute(chk.clone()).is_ok());
+
+        let mut vm = VM::new(false);
+        vm.set_timeout(Duration::from_nanos(1));
+        assert_eq!(vm.execute(chk.clone()).unwrap_err().message, "Execution timed out");
+
+        //pausing between steps doesn't use up the budget
+        let mut vm = VM::new(false);
+        vm.set_timeout(Duration::from_millis(50));
+        vm.reset(chk);
+        for _ in 0..CHECK_INTERVAL - 1 {
+            assert_eq!(vm.step(), Ok(StepResult::Continue));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut chk = Chunk::new();
+        for _ in 0..200 {
+            chk.add_instr(Instruction::PushNull, 0);
+            chk.add_instr(Instruction::Pop, 0);
+        }
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        assert!(vm.execute(chk.clone()).is_ok());
+        assert_eq!(vm.instructions_executed(), 401);
+
+        let mut vm = VM::new(false);
+        vm.set_instruction_limit(100);
+        assert_eq!(vm.execute(chk).unwrap_err().message, "Instruction budget exhausted");
+        assert_eq!(vm.instructions_executed(), 100);
+    }
+
+    #[test]
+    fn test_float_precision() {
+        let mut chk = Chunk::new();
+
+        chk.add_const(Value::String("x is ".to_string()));
+        chk.add_const(Value::Number(1.23456));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+        chk.add_instr(Instruction::PushConstant(1), 0);
+        chk.add_instr(Instruction::Add, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        vm.set_float_precision(3);
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::String("x is 1.23".to_string())]);
+    }
+
+    #[test]
+    fn test_trace_labeled() {
+        let mut chk = Chunk::new();
+
+        chk.add_const(Value::Number(5.));
+        chk.add_const(Value::String("total".to_string()));
+        chk.add_instr(Instruction::PushConstant(0), 1);
+        chk.add_instr(Instruction::TraceLabeled(1), 2);
+        chk.add_instr(Instruction::Trace, 3);
+        chk.add_instr(Instruction::Halt, 3);
+
+        let buf = SharedBuf::default();
+        let mut vm = VM::with_output(false, Box::new(buf.clone()));
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack, vec![Value::Number(5.)]);
+        assert_eq!(buf.text(), "[2] total: 5\n[3] 5\n");
+    }
+
+    #[test]
+    fn test_output() {
+        let mut chk = Chunk::new();
+
+        chk.add_const(Value::Number(5.));
+        chk.add_const(Value::String("x".to_string()));
+        chk.add_instr(Instruction::PushConstant(0), 1);
+        chk.add_instr(Instruction::Trace, 1);
+        chk.add_instr(Instruction::TraceLabeled(1), 2);
+        chk.add_instr(Instruction::Halt, 2);
+
+        let buf = SharedBuf::default();
+        let mut vm = VM::with_output(false, Box::new(buf.clone()));
+
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(buf.text(), "[1] 5\n[2] x: 5\n");
+    }
+
+    #[test]
+    fn test_non_finite_output() {
+        let mut chk = Chunk::new();
+
+        chk.add_push_const(Value::Number(10.), 1);
+        chk.add_push_const(Value::Number(400.), 1);
+        chk.add_instr(Instruction::Exponent, 1);
+        chk.add_instr(Instruction::Trace, 1);
+        chk.add_push_const(Value::String("=".to_string()), 1);
+        chk.add_instr(Instruction::Swap, 1);
+        chk.add_instr(Instruction::Add, 1);
+        chk.add_instr(Instruction::Halt, 1);
+
+        let mut vm = VM::new(false);
+
+        assert_eq!(vm.run_capturing(chk), Ok((Value::String("=Infinity".to_string()), "[1] Infinity\n".to_string())));
+    }
+
+    #[test]
+    fn test_run_capturing() {
+        let mut chk = Chunk::new();
+
+        chk.add_push_const(Value::Int(2), 1);
+        chk.add_instr(Instruction::Trace, 1);
+        chk.add_push_const(Value::Int(3), 2);
+        chk.add_instr(Instruction::Multiply, 2);
+        chk.add_instr(Instruction::Halt, 2);
+
+        let buf = SharedBuf::default();
+        let mut vm = VM::with_output(false, Box::new(buf.clone()));
+
+        assert_eq!(vm.run_capturing(chk), Ok((Value::Int(6), "[1] 2\n".to_string())));
+        assert_eq!(buf.text(), "");
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Number(1.23456), 1);
+        chk.add_instr(Instruction::Trace, 1);
+        chk.add_instr(Instruction::Halt, 1);
+
+        let buf = SharedBuf::default();
+        let mut vm = VM::builder()
+            .output(Box::new(buf.clone()))
+            .float_precision(3)
+            .profiling(true)
+            .build();
+
+        assert!(vm.execute(chk.clone()).is_ok());
+        assert_eq!(buf.text(), "[1] 1.23\n");
+        assert_eq!(vm.profile().unwrap().get("Trace"), Some(&1));
+
+        let mut vm = VM::builder().output(Box::new(SharedBuf::default())).instruction_limit(2).build();
+        assert_eq!(vm.execute(chk).unwrap_err().message, "Instruction budget exhausted");
+    }
+
+    #[test]
+    fn test_run_chunk() {
+        assert_eq!(run_chunk(arithmetic_chunk(), RunOptions::default()), Ok(Value::Number(5.)));
+
+        let buf = SharedBuf::default();
+        let opts = RunOptions {
+            output: Some(Box::new(buf.clone())),
+            ..RunOptions::default()
+        };
+        assert_eq!(run_chunk(kwexpr_chunk(), opts), Ok(Value::Number(5.)));
+        assert_eq!(buf.text(), "[0] 5\n");
+
+        let opts = RunOptions {
+            instruction_limit: Some(3),
+            ..RunOptions::default()
+        };
+        assert_eq!(run_chunk(arithmetic_chunk(), opts).unwrap_err().message, "Instruction budget exhausted");
+    }
+
+    #[test]
+    fn test_serialized_chunk() {
+        let mut chk = Chunk::new();
+        chk.add_line(0);
+
+        chk.add_const(Value::Number(5.));
+        chk.add_instr(Instruction::PushConstant(0), 0);
+
+        chk.add_instr(Instruction::Trace, 0);
+
+        chk.add_instr(Instruction::Halt, 0);
+
+        let loaded = Chunk::deserialize(&chk.serialize().unwrap()).expect("round trip failed");
+
+        let (buf, loaded_buf) = (SharedBuf::default(), SharedBuf::default());
+        let mut vm = VM::with_output(false, Box::new(buf.clone()));
+        assert!(vm.execute(chk).is_ok());
+        let mut vm = VM::with_output(false, Box::new(loaded_buf.clone()));
+        assert!(vm.execute(loaded).is_ok());
+
+        assert_eq!(buf.text(), "[0] 5\n");
+        assert_eq!(buf.text(), loaded_buf.text());
+    }
+
+    #[test]
+    fn test_stack_dump() {
+        let mut chk = Chunk::new();
+        chk.add_push_const(Value::Int(1), 0);
+        chk.add_push_const(Value::Int(2), 0);
+        chk.add_push_const(Value::Int(3), 0);
+        chk.add_instr(Instruction::NewArray(3), 0);
+        chk.add_push_const(Value::String("hello".to_string()), 0);
+        chk.add_instr(Instruction::NewMap(0), 0);
+        chk.add_instr(Instruction::PushNull, 0);
+        chk.add_instr(Instruction::Halt, 0);
+
+        let mut vm = VM::new(false);
+        assert_eq!(vm.stack_dump(), "stack <empty>");
+        assert!(vm.execute(chk).is_ok());
+        assert_eq!(vm.stack_dump(), "stack | array[3] | \"hello\" | map[0] | Null ");
+
+        //nested collections are summarized too, not expanded
+        let nested = Value::Array(Rc::new(RefCell::new(vec![vm.stack[0].clone()])));
+        vm.stack = vec![nested];
+        assert_eq!(vm.stack_dump(), "stack | array[1] ");
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let mut chk = Chunk::new();
+
+        chk.add_const(Value::Number(5.));
+        chk.add_instr(Instruction::PushConstant(0), 1);
+        chk.add_instr(Instruction::PushConstant(0), 1);
+        chk.add_instr(Instruction::Add, 2);
+        chk.add_instr(Instruction::Halt, 2);
+
+        let mut vm = VM::new(true);
+        vm.set_trace_format(TraceFormat::Json);
+        vm.chk = chk;
+
+        assert_eq!(vm.json_trace_line(), r#"{"ip":0,"op":"PushConstant","stack_depth":0,"line":1}"#);
+
+        vm.cur_instr = 2;
+        vm.stack.push(Value::Number(5.));
+        vm.stack.push(Value::Number(5.));
+        assert_eq!(vm.json_trace_line(), r#"{"ip":2,"op":"Add","stack_depth":2,"line":2}"#);
+
+        vm.stack.clear();
+        let chk = vm.chk.clone();
+        let (res, out) = vm.run_capturing(chk).expect("the chunk runs");
+        assert_eq!(res, Value::Number(10.));
+        assert_eq!(out.lines().collect::<Vec<&str>>(), vec![
+            r#"{"ip":0,"op":"PushConstant","stack_depth":0,"line":1}"#,
+            r#"{"ip":1,"op":"PushConstant","stack_depth":1,"line":1}"#,
+            r#"{"ip":2,"op":"Add","stack_depth":2,"line":2}"#,
+            r#"{"ip":3,"op":"Halt","stack_depth":1,"line":2}"#
+        ]);
     }
 }
\ No newline at end of file